@@ -0,0 +1,137 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// Usage: cargo run --example alloc_profile
+//
+// Wraps the system allocator to attribute allocated bytes to call-stacks with
+// `tracefp::events`. Capturing a stack for every allocation would be slow, so
+// one is captured per `SAMPLE_BYTES` allocated on a thread, weighted with the
+// bytes allocated since the previous one.
+fn main() {
+    allocate_strings();
+    allocate_vectors();
+    for (bytes, name) in top(10) {
+        println!("{:>10} bytes  {}", bytes, name);
+    }
+}
+
+const SAMPLE_BYTES: u64 = 64 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: Profiled = Profiled;
+
+struct Profiled;
+
+thread_local! {
+    // The bytes allocated since the last sample.
+    static ALLOCATED: Cell<u64> = const { Cell::new(0) };
+    // Whether the thread is inside `events::record` already, which allocates
+    // itself.
+    static RECORDING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for Profiled {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        sample(layout.size() as u64);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn sample(size: u64) {
+    // Fails during thread teardown, when these are gone.
+    let _ = RECORDING.try_with(|recording| {
+        if recording.get() {
+            return;
+        }
+        let allocated = ALLOCATED.with(|v| v.get()) + size;
+        if allocated < SAMPLE_BYTES {
+            ALLOCATED.with(|v| v.set(allocated));
+            return;
+        }
+        ALLOCATED.with(|v| v.set(0));
+        recording.set(true);
+        tracefp::events::record("alloc_bytes", allocated);
+        recording.set(false);
+    });
+}
+
+// The `n` functions with the most allocated bytes, counting the innermost
+// frame outside of the allocator.
+fn top(n: usize) -> Vec<(u64, String)> {
+    let stacks = tracefp::events::take();
+    let mut functions = std::collections::HashMap::new();
+    for stack in stacks.iter().filter(|s| s.kind == "alloc_bytes") {
+        let name = stack
+            .stack
+            .iter()
+            .map(|pc| resolve(*pc))
+            .find(|name| !is_allocator(name))
+            .unwrap_or_default();
+        *functions.entry(name).or_default() += stack.weight;
+    }
+    let mut functions: Vec<_> = functions.into_iter().map(|(name, bytes)| (bytes, name)).collect();
+    functions.sort_unstable_by(|a, b| b.cmp(a));
+    functions.truncate(n);
+    functions
+}
+
+fn is_allocator(name: &str) -> bool {
+    [
+        "tracefp::",
+        "alloc_profile::sample",
+        "alloc::",
+        "core::",
+        "std::",
+        "<alloc_profile::Profiled",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+        || name.contains("__rust")
+}
+
+fn resolve(pc: u64) -> String {
+    let mut name = None;
+    backtrace::resolve(pc as _, |s| {
+        if name.is_none() {
+            name = s.name().map(|n| format!("{:#}", n));
+        }
+    });
+    name.unwrap_or_else(|| format!("{:#x}", pc))
+}
+
+#[inline(never)]
+fn allocate_strings() {
+    let strings: Vec<String> = (0..10_000).map(|n| format!("string number {}", n)).collect();
+    std::hint::black_box(strings);
+}
+
+#[inline(never)]
+fn allocate_vectors() {
+    for n in 0..100 {
+        std::hint::black_box(vec![n as u8; 100 * 1024]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_profile() {
+        tracefp::events::take();
+        allocate_vectors();
+        let top = top(usize::MAX);
+        let bytes: u64 = top.iter().map(|(bytes, _)| bytes).sum();
+        // Every allocation of `allocate_vectors` is sampled.
+        assert!(bytes >= 100 * 100 * 1024, "{:?}", top);
+        assert!(
+            top.iter().any(|(_, name)| name.contains("allocate_vectors")),
+            "{:?}",
+            top
+        );
+    }
+}
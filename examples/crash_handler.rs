@@ -0,0 +1,177 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Usage: cargo run --example crash_handler
+//
+// Prints the call-stack of the crashing thread, then crashes as usual. The
+// handler only does async-signal-safe work: no allocation, no locks, nothing
+// but `write(2)`. Resolve the printed PCs offline, e.g. with `addr2line`.
+fn main() {
+    install(libc::STDERR_FILENO, alt_stack()).unwrap();
+    crash();
+}
+
+// The file descriptor the handler writes to.
+static FD: AtomicI32 = AtomicI32::new(libc::STDERR_FILENO);
+
+// Allocate a stack for the handler to run on, so that it survives overflows
+// of the thread's one. The stack is leaked with the handler.
+fn alt_stack() -> libc::stack_t {
+    let size = 64 * 1024;
+    libc::stack_t {
+        ss_sp: Box::leak(vec![0u8; size].into_boxed_slice()).as_mut_ptr() as *mut libc::c_void,
+        ss_flags: 0,
+        ss_size: size,
+    }
+}
+
+// Install the handler for the signals of crashes on `stack`, writing to `fd`.
+//
+// Only makes async-signal-safe calls and does not allocate, so it may run in
+// the child of a fork of a multi-threaded process.
+fn install(fd: libc::c_int, stack: libc::stack_t) -> std::io::Result<()> {
+    FD.store(fd, Ordering::Relaxed);
+    unsafe {
+        if libc::sigaltstack(&stack, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) = handler;
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        // Restore the default action on entry, so that re-raising the
+        // signal kills the process.
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+        for signal in [libc::SIGSEGV, libc::SIGBUS, libc::SIGILL, libc::SIGFPE, libc::SIGABRT] {
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handler(signal: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+    let fd = FD.load(Ordering::Relaxed);
+    let mut line = Line::new();
+    line.push(b"crashed with signal ");
+    line.push_number(signal as u64, 10);
+    line.write(fd);
+    let mut n = 0;
+    tracefp::trace_from_ucontext(ucontext, |pc| {
+        let mut line = Line::new();
+        line.push(b"#");
+        line.push_number(n, 10);
+        line.push(b" 0x");
+        line.push_number(pc, 16);
+        line.write(fd);
+        n += 1;
+        true
+    });
+    // The default action is back, so this terminates the process once the
+    // handler returns.
+    unsafe { libc::raise(signal) };
+}
+
+// A line of output, formatted without allocating.
+struct Line {
+    data: [u8; 64],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Self { data: [0; 64], len: 0 }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    fn push_number(&mut self, mut value: u64, radix: u64) {
+        let mut digits = [0u8; 20];
+        let mut n = digits.len();
+        loop {
+            n -= 1;
+            digits[n] = b"0123456789abcdef"[(value % radix) as usize];
+            value /= radix;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[n..]);
+    }
+
+    fn write(mut self, fd: libc::c_int) {
+        self.push(b"\n");
+        unsafe { libc::write(fd, self.data.as_ptr() as *const libc::c_void, self.len) };
+    }
+}
+
+#[inline(never)]
+fn crash() {
+    // Read from an unmapped address.
+    let address = std::hint::black_box(8usize);
+    unsafe { std::ptr::read_volatile(address as *const u64) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crash_handler() {
+        // Crash in a child, which reports through a pipe. The test harness
+        // runs other threads, so the child may only make async-signal-safe
+        // calls: everything is allocated before the fork.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let stack = alt_stack();
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            if install(fds[1], stack).is_err() {
+                unsafe { libc::_exit(1) };
+            }
+            crash();
+            unsafe { libc::_exit(0) };
+        }
+        unsafe { libc::close(fds[1]) };
+        let mut out = vec![];
+        let mut buffer = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(fds[0], buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if n <= 0 {
+                break;
+            }
+            out.extend_from_slice(&buffer[..n as usize]);
+        }
+        let mut status = 0;
+        unsafe {
+            libc::close(fds[0]);
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        }
+        assert!(libc::WIFSIGNALED(status));
+        let signal = libc::WTERMSIG(status);
+        assert!(signal == libc::SIGSEGV || signal == libc::SIGBUS);
+
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some(format!("crashed with signal {}", signal).as_str()));
+        let pcs: Vec<_> = lines
+            .map(|line| {
+                let (_, pc) = line.split_once(" 0x").unwrap();
+                u64::from_str_radix(pc, 16).unwrap()
+            })
+            .collect();
+        assert!(pcs.len() > 1);
+        // The child is a fork, so its PCs resolve here.
+        let mut names = vec![];
+        for pc in pcs {
+            backtrace::resolve(pc as _, |s| names.extend(s.name().map(|n| format!("{:#}", n))));
+        }
+        assert!(names.iter().any(|n| n == "crash_handler::crash"), "{:?}", names);
+    }
+}
@@ -0,0 +1,97 @@
+// Usage: cargo run --example dump <pid>
+#[cfg(target_os = "linux")]
+fn main() {
+    let pid: libc::pid_t = match std::env::args().nth(1).and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => {
+            eprintln!("usage: dump <pid>");
+            std::process::exit(2);
+        }
+    };
+    dump(pid, &mut std::io::stdout().lock()).unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("dump is only supported on Linux");
+}
+
+// Write the call-stacks of all threads of the process `pid` to `out`.
+#[cfg(target_os = "linux")]
+fn dump<W: std::io::Write>(pid: libc::pid_t, out: &mut W) -> std::io::Result<()> {
+    use tracefp::modules::ModuleMap;
+    use tracefp::remote::Thread;
+    use tracefp::symbols::SymbolTable;
+
+    let modules = ModuleMap::for_process(pid)?;
+    let symbols = SymbolTable::for_modules(&modules);
+
+    let mut tids: Vec<libc::pid_t> = std::fs::read_dir(format!("/proc/{}/task", pid))?
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    tids.sort_unstable();
+    for tid in tids {
+        let name = std::fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid)).unwrap_or_default();
+        writeln!(out, "thread {} ({}):", tid, name.trim_end())?;
+        let thread = match Thread::attach(tid) {
+            Ok(v) => v,
+            Err(err) => {
+                writeln!(out, "    {}", err)?;
+                continue;
+            }
+        };
+        let mut n = 0;
+        let mut written = Ok(());
+        let res = thread.trace(|pc| {
+            let symbol = symbols.resolve_symbol(pc);
+            let module = modules.lookup(pc);
+            written = match (symbol, module) {
+                (Some(s), _) => writeln!(out, "#{:<3} {:#018x} {}+{:#x}", n, pc, s.name, pc - s.address),
+                (None, Some((m, offset))) => writeln!(out, "#{:<3} {:#018x} {}+{:#x}", n, pc, m.path.display(), offset),
+                (None, None) => writeln!(out, "#{:<3} {:#018x}", n, pc),
+            };
+            n += 1;
+            written.is_ok()
+        });
+        written?;
+        if let Err(err) = res {
+            writeln!(out, "    {}", err)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    // Spins in code with frame pointers.
+    #[inline(never)]
+    fn spin() -> ! {
+        loop {
+            std::hint::spin_loop();
+        }
+    }
+
+    #[test]
+    fn test_dump() {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            spin();
+        }
+        let mut out = vec![];
+        let res = dump(pid, &mut out);
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+        res.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&format!("thread {} (", pid)), "{}", out);
+        // Sandboxes commonly forbid ptrace, which is reported per thread.
+        if !out.contains("Operation not permitted") {
+            assert!(out.contains("\n#0 "), "{}", out);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use tracefp::profiler::Profiler;
+
+// Usage: cargo run --example flamegraph [seconds] > profile.folded
+//
+// The output is in the folded format of `flamegraph.pl` and `inferno`:
+//
+//     inferno-flamegraph < profile.folded > profile.svg
+fn main() {
+    let seconds = std::env::args().nth(1).and_then(|v| v.parse().ok()).unwrap_or(5);
+    profile(Duration::from_secs(seconds), io::stdout().lock()).unwrap();
+}
+
+// Profile some busy work for `duration` and write the folded stacks to `out`.
+fn profile<W: Write>(duration: Duration, out: W) -> io::Result<()> {
+    let profiler = Profiler::start(999)?;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        busy_hash();
+        busy_sort();
+    }
+    let report = profiler.stop();
+    eprintln!("{} samples, {} dropped", report.samples(), report.dropped);
+
+    let mut names = HashMap::new();
+    report.folded(out, |pc| names.entry(pc).or_insert_with(|| resolve(pc)).clone())
+}
+
+fn resolve(pc: u64) -> String {
+    let mut name = None;
+    backtrace::resolve(pc as _, |s| {
+        if name.is_none() {
+            name = s.name().map(|n| format!("{:#}", n));
+        }
+    });
+    name.unwrap_or_else(|| format!("{:#x}", pc))
+}
+
+#[inline(never)]
+fn busy_hash() {
+    let mut hash = 0u64;
+    for n in 0..100_000u64 {
+        hash = std::hint::black_box(hash.rotate_left(5) ^ n);
+    }
+}
+
+#[inline(never)]
+fn busy_sort() {
+    let mut values: Vec<u64> = (0..10_000u64).map(|n| n.wrapping_mul(0x9e37_79b9_7f4a_7c15)).collect();
+    values.sort_unstable();
+    std::hint::black_box(values);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flamegraph() {
+        let mut out = vec![];
+        profile(Duration::from_millis(300), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.is_empty());
+        for line in out.lines() {
+            let (stack, count) = line.rsplit_once(' ').unwrap();
+            assert!(!stack.is_empty());
+            assert!(count.parse::<u64>().unwrap() > 0);
+        }
+        assert!(out.contains("busy_hash") || out.contains("busy_sort"));
+    }
+}
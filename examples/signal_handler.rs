@@ -1,6 +1,15 @@
+use std::sync::OnceLock;
+
 use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGPROF};
+use tracefp::symbols::SymbolTable;
+
+// Loading symbols allocates and reads files, so it is done before the
+// handler is installed.
+static SYMBOLS: OnceLock<SymbolTable> = OnceLock::new();
 
 fn main() {
+    SYMBOLS.set(SymbolTable::load()).unwrap();
+
     // Register perf signal handler.
     let h = SigHandler::SigAction(perf_signal_handler);
     let a = SigAction::new(h, SaFlags::SA_SIGINFO, SigSet::empty());
@@ -14,17 +23,43 @@ fn main() {
     }
 
     // Block until the signal handler finishes executing.
-    loop {}
+    loop {
+        std::thread::park();
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn perf_signal_handler(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+    // Only async-signal-safe calls in here: no allocation, no locks, so no
+    // `println!` either.
+    let symbols = SYMBOLS.get();
     tracefp::trace_from_ucontext(ucontext, |pc| {
-        println!("{:#x}", pc);
-        backtrace::resolve(pc as _, |s| {
-            println!("    {:?}", s.name());
-        });
+        write(hex(pc, &mut [0; 18]));
+        if let Some(symbol) = symbols.and_then(|s| s.resolve_symbol(pc)) {
+            write(b"\n    ");
+            write(symbol.name.as_bytes());
+        }
+        write(b"\n");
         true
     });
-    std::process::exit(0);
+    unsafe { libc::_exit(0) };
+}
+
+fn write(bytes: &[u8]) {
+    unsafe { libc::write(libc::STDOUT_FILENO, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+}
+
+// Format `value` as `0x...` into `buffer`.
+fn hex(mut value: u64, buffer: &mut [u8; 18]) -> &[u8] {
+    let mut n = buffer.len();
+    loop {
+        n -= 1;
+        buffer[n] = b"0123456789abcdef"[(value & 0xf) as usize];
+        value >>= 4;
+        if value == 0 {
+            break;
+        }
+    }
+    buffer[n - 2..n].copy_from_slice(b"0x");
+    &buffer[n - 2..]
 }
@@ -0,0 +1,124 @@
+// Usage: cargo run --example thread_dump, then `kill -QUIT <pid>`
+//
+// Like a JVM, the process prints the call-stacks of all its threads on
+// `SIGQUIT` and keeps running. `trace_all_threads` must not be called from a
+// signal handler, so the signal is blocked everywhere and a dedicated thread
+// waits for it with `sigwait`.
+#[cfg(target_os = "linux")]
+fn main() {
+    use std::time::Duration;
+
+    // Block before spawning, threads inherit the mask.
+    let dumper = on_sigquit(|| dump(&mut std::io::stdout().lock()).unwrap());
+    for n in 0..2 {
+        std::thread::Builder::new()
+            .name(format!("worker-{}", n))
+            .spawn(move || {
+                // Shown as `worker-pool(n)` in the dump.
+                tracefp::set_pool_thread("worker-pool", n);
+                loop {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            })
+            .unwrap();
+    }
+    println!("pid {}, send SIGQUIT to dump the threads", std::process::id());
+    dumper.join().unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("thread_dump is only supported on Linux");
+}
+
+// Block `SIGQUIT` in the calling thread and spawn one that calls `f` on
+// every `SIGQUIT`.
+#[cfg(target_os = "linux")]
+fn on_sigquit<F: Fn() + Send + 'static>(f: F) -> std::thread::JoinHandle<()> {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGQUIT);
+        assert_eq!(libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()), 0);
+    }
+    std::thread::Builder::new()
+        .name("thread-dump".to_string())
+        .spawn(move || loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                f();
+            }
+        })
+        .unwrap()
+}
+
+// Write the call-stacks of all threads to `out`.
+#[cfg(target_os = "linux")]
+fn dump<W: std::io::Write>(out: &mut W) -> std::io::Result<()> {
+    use std::time::Duration;
+
+    for thread in tracefp::trace_all_threads(Duration::from_millis(100))? {
+        match &thread.pool {
+            Some(pool) => writeln!(out, "thread {} ({}, {}):", thread.tid, thread.name, pool)?,
+            None => writeln!(out, "thread {} ({}):", thread.tid, thread.name)?,
+        }
+        if thread.frames.is_empty() {
+            writeln!(out, "    <no response>")?;
+        }
+        for (n, frame) in thread.frames.iter().enumerate() {
+            let mut name = None;
+            backtrace::resolve(frame.pc as _, |s| {
+                if name.is_none() {
+                    name = s.name().map(|n| format!("{:#}", n));
+                }
+            });
+            writeln!(out, "#{:<3} {:#018x} {}", n, frame.pc, name.unwrap_or_default())?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_thread_dump() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let dumper = on_sigquit(move || {
+            let mut out = vec![];
+            dump(&mut out).unwrap();
+            tx.lock().unwrap().send(String::from_utf8(out).unwrap()).unwrap();
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("worker".to_string())
+                .spawn(move || {
+                    tracefp::set_pool_thread("pool", 7);
+                    while !stop.load(Ordering::Relaxed) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                })
+                .unwrap()
+        };
+
+        // The test harness does not block `SIGQUIT` in its other threads, so
+        // send it to the dumper only.
+        assert_eq!(unsafe { libc::pthread_kill(dumper.as_pthread_t(), libc::SIGQUIT) }, 0);
+        let out = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        stop.store(true, Ordering::Relaxed);
+        worker.join().unwrap();
+
+        assert!(out.contains("(worker, pool(7)):\n#0"), "{}", out);
+        assert!(out.contains("(thread-dump):\n#0"), "{}", out);
+        assert!(out.contains("dump"), "{}", out);
+    }
+}
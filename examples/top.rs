@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tracefp::profiler::Profiler;
+
+// Usage: cargo run --example top [seconds]
+fn main() {
+    let seconds = std::env::args().nth(1).and_then(|v| v.parse().ok()).unwrap_or(10);
+
+    // Something to look at.
+    for n in 0..2 {
+        std::thread::spawn(move || loop {
+            if n == 0 {
+                busy_hash();
+            } else {
+                busy_sort();
+            }
+        });
+    }
+
+    let profiler = Profiler::start(199).unwrap();
+    let mut names = HashMap::new();
+    for _ in 0..seconds {
+        std::thread::sleep(Duration::from_secs(1));
+        let report = profiler.report();
+
+        // Samples per function, of the innermost frame and of all frames.
+        let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+        for stack in &report.stacks {
+            let mut seen = vec![];
+            for (depth, pc) in stack.stack.iter().enumerate() {
+                let name = names.entry(*pc).or_insert_with(|| resolve(*pc)).clone();
+                if seen.contains(&name) {
+                    continue;
+                }
+                let entry = functions.entry(name.clone()).or_default();
+                if depth == 0 {
+                    entry.0 += stack.count;
+                }
+                entry.1 += stack.count;
+                seen.push(name);
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by_key(|(_, counts)| std::cmp::Reverse(*counts));
+
+        let total = report.samples().max(1) as f64;
+        print!("\x1b[2J\x1b[H");
+        println!("{} samples, {} dropped\n", report.samples(), report.dropped);
+        println!("{:>7} {:>7}  function", "self", "total");
+        for (name, (own, all)) in functions.iter().take(20) {
+            println!(
+                "{:>6.1}% {:>6.1}%  {}",
+                *own as f64 * 100.0 / total,
+                *all as f64 * 100.0 / total,
+                name
+            );
+        }
+    }
+}
+
+fn resolve(pc: u64) -> String {
+    let mut name = None;
+    backtrace::resolve(pc as _, |s| {
+        if name.is_none() {
+            name = s.name().map(|n| format!("{:#}", n));
+        }
+    });
+    name.unwrap_or_else(|| format!("{:#x}", pc))
+}
+
+#[inline(never)]
+fn busy_hash() {
+    let mut hash = 0u64;
+    for n in 0..1_000_000u64 {
+        hash = std::hint::black_box(hash.rotate_left(5) ^ n);
+    }
+}
+
+#[inline(never)]
+fn busy_sort() {
+    let mut values: Vec<u64> = (0..100_000u64).map(|n| n.wrapping_mul(0x9e37_79b9_7f4a_7c15)).collect();
+    values.sort_unstable();
+    std::hint::black_box(values);
+}
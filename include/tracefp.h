@@ -0,0 +1,37 @@
+/*
+ * C interface of tracefp, a stack backtracking library based on
+ * frame-pointer. Build tracefp with the `capi` feature to get these symbols,
+ * see `src/capi.rs`.
+ */
+
+#ifndef TRACEFP_H
+#define TRACEFP_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Called with every PC of a call-stack, innermost frame first, and the
+ * `user_data` given to the tracing function. Returns nonzero to continue
+ * with the next frame.
+ */
+typedef int (*tracefp_callback)(uint64_t pc, void *user_data);
+
+/* Passes all active PCs of the current call-stack into `callback`. */
+void tracefp_trace(tracefp_callback callback, void *user_data);
+
+/*
+ * Passes all active PCs of the call-stack interrupted by a signal into
+ * `callback`. `ucontext` is the third argument of a SA_SIGINFO signal
+ * handler. Async-signal-safe if `callback` is.
+ */
+void tracefp_trace_from_ucontext(void *ucontext, tracefp_callback callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TRACEFP_H */
@@ -0,0 +1,247 @@
+//! Deduplication of call-stacks.
+//!
+//! [`StackId`] is a stable hash of a stack, and [`StackAggregator`] counts
+//! identical stacks in a preallocated table as they are captured. Recording
+//! takes no locks and does not allocate, so it can be done from a signal
+//! handler, which keeps only one copy of every distinct stack in memory
+//! instead of every raw sample.
+//!
+//! ```rust
+//! use tracefp::aggregate::StackAggregator;
+//!
+//! let stacks = StackAggregator::new(1024);
+//! for _ in 0..3 {
+//!     stacks.record(&[0x1000, 0x2000]);
+//! }
+//! stacks.drain(|id, pcs, count| println!("{:016x} {:x?}: {}", id.0, pcs, count));
+//! ```
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::sample::MAX_SAMPLE_FRAMES;
+
+/// A stable 64-bit hash of the PCs of a stack.
+///
+/// The hash is FNV-1a over the little-endian bytes of the PCs, so the same
+/// stack has the same id in every process and on every platform. It is never
+/// 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StackId(pub u64);
+
+impl StackId {
+    /// Hashes `pcs`, innermost frame first.
+    pub fn new(pcs: &[u64]) -> Self {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for pc in pcs {
+            for byte in pc.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        Self(hash.max(1))
+    }
+}
+
+struct Entry {
+    // 0 while the entry is free.
+    id: AtomicU64,
+    // Whether `len` and `pcs` are written.
+    ready: AtomicBool,
+    count: AtomicU64,
+    len: UnsafeCell<usize>,
+    pcs: UnsafeCell<[u64; MAX_SAMPLE_FRAMES]>,
+}
+
+/// Counts identical stacks in a bounded hash table, see the
+/// [module documentation](self).
+///
+/// Stacks are identified by their [`StackId`] alone, two stacks with
+/// colliding hashes are counted as one. Once the table is full, new stacks
+/// are only counted in [`dropped`](StackAggregator::dropped). Stacks are
+/// never removed, [`drain`](StackAggregator::drain) only resets the counts.
+pub struct StackAggregator {
+    entries: Box<[Entry]>,
+    dropped: AtomicU64,
+}
+
+// `len` and `pcs` of an entry are written once, by the thread that claimed
+// it, before `ready` is set.
+unsafe impl Sync for StackAggregator {}
+
+impl StackAggregator {
+    /// Creates a table for `capacity` distinct stacks, rounded up to a power
+    /// of two.
+    pub fn new(capacity: usize) -> Self {
+        let entries = (0..capacity.max(1).next_power_of_two())
+            .map(|_| Entry {
+                id: AtomicU64::new(0),
+                ready: AtomicBool::new(false),
+                count: AtomicU64::new(0),
+                len: UnsafeCell::new(0),
+                pcs: UnsafeCell::new([0; MAX_SAMPLE_FRAMES]),
+            })
+            .collect();
+        Self {
+            entries,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of distinct stacks the table holds.
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The number of recordings that found the table full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Counts the stack `pcs`, cut off after [`MAX_SAMPLE_FRAMES`] frames.
+    /// Returns its id, or `None` if the table is full. Async-signal-safe.
+    pub fn record(&self, pcs: &[u64]) -> Option<StackId> {
+        let pcs = &pcs[..pcs.len().min(MAX_SAMPLE_FRAMES)];
+        let id = StackId::new(pcs);
+        let mask = self.entries.len() - 1;
+        for n in 0..self.entries.len() {
+            let entry = &self.entries[(id.0 as usize).wrapping_add(n) & mask];
+            let current = match entry.id.compare_exchange(0, id.0, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    let (buffer, len) = unsafe { (&mut *entry.pcs.get(), &mut *entry.len.get()) };
+                    buffer[..pcs.len()].copy_from_slice(pcs);
+                    *len = pcs.len();
+                    entry.ready.store(true, Ordering::Release);
+                    id.0
+                }
+                Err(v) => v,
+            };
+            if current == id.0 {
+                entry.count.fetch_add(1, Ordering::Relaxed);
+                return Some(id);
+            }
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// Unwinds the call-stack of a signal handler's `ucontext` like
+    /// [`trace_from_ucontext`](crate::trace_from_ucontext) and counts it.
+    /// Async-signal-safe.
+    pub fn record_from_ucontext(&self, ucontext: *mut libc::c_void) -> Option<StackId> {
+        let mut pcs = [0; MAX_SAMPLE_FRAMES];
+        let mut len = 0;
+        crate::trace_from_ucontext(ucontext, |pc| {
+            pcs[len] = pc;
+            len += 1;
+            len < MAX_SAMPLE_FRAMES
+        });
+        self.record(&pcs[..len])
+    }
+
+    /// Passes every stack with its count into the closure provided.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(StackId, &[u64], u64),
+    {
+        self.visit(|entry| entry.count.load(Ordering::Relaxed), &mut f)
+    }
+
+    /// Passes every stack counted since the last call with the number of
+    /// times it was counted into the closure provided, and resets the counts.
+    pub fn drain<F>(&self, mut f: F)
+    where
+        F: FnMut(StackId, &[u64], u64),
+    {
+        self.visit(|entry| entry.count.swap(0, Ordering::Relaxed), &mut f)
+    }
+
+    fn visit<C, F>(&self, count: C, f: &mut F)
+    where
+        C: Fn(&Entry) -> u64,
+        F: FnMut(StackId, &[u64], u64),
+    {
+        for entry in self.entries.iter() {
+            if !entry.ready.load(Ordering::Acquire) {
+                continue;
+            }
+            let count = count(entry);
+            if count > 0 {
+                let (pcs, len) = unsafe { (&*entry.pcs.get(), *entry.len.get()) };
+                f(StackId(entry.id.load(Ordering::Relaxed)), &pcs[..len], count);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for StackAggregator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StackAggregator")
+            .field("capacity", &self.capacity())
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(stacks: &StackAggregator) -> Vec<(Vec<u64>, u64)> {
+        let mut counts = vec![];
+        stacks.drain(|id, pcs, count| {
+            assert_eq!(id, StackId::new(pcs));
+            counts.push((pcs.to_vec(), count));
+        });
+        counts.sort();
+        counts
+    }
+
+    #[test]
+    fn test_stack_id() {
+        assert_eq!(StackId::new(&[]), StackId(0xcbf2_9ce4_8422_2325));
+        assert_eq!(StackId::new(&[1, 2]), StackId::new(&[1, 2]));
+        assert_ne!(StackId::new(&[1, 2]), StackId::new(&[2, 1]));
+    }
+
+    #[test]
+    fn test_stack_aggregator() {
+        let stacks = StackAggregator::new(3);
+        assert_eq!(stacks.capacity(), 4);
+        for pcs in [&[1, 2][..], &[3], &[1, 2], &[4], &[5], &[1, 2]] {
+            assert_eq!(stacks.record(pcs), Some(StackId::new(pcs)));
+        }
+        assert_eq!(stacks.record(&[6]), None);
+        assert_eq!(stacks.dropped(), 1);
+        assert_eq!(
+            counts(&stacks),
+            [(vec![1, 2], 3), (vec![3], 1), (vec![4], 1), (vec![5], 1)]
+        );
+        // Counts are reset, the stacks are kept.
+        assert!(counts(&stacks).is_empty());
+        stacks.record(&[4]);
+        assert_eq!(counts(&stacks), [(vec![4], 1)]);
+
+        let mut total = 0;
+        stacks.for_each(|_, _, count| total += count);
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let stacks = StackAggregator::new(16);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for n in 0..1000 {
+                        stacks.record(&[n % 8, 42]);
+                    }
+                });
+            }
+        });
+        let counts = counts(&stacks);
+        assert_eq!(counts.len(), 8);
+        assert!(counts.iter().all(|(pcs, count)| pcs[1] == 42 && *count == 500));
+    }
+}
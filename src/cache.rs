@@ -0,0 +1,189 @@
+//! A persistent cache of symbolization results.
+//!
+//! Reading the symbols of all modules again after every restart of a
+//! long-running agent is wasted work if the same binaries are symbolized
+//! over and over. A [`SymbolCache`] keeps the names found for
+//! `(build-id, offset)` pairs, as produced by
+//! [`ModuleMap::lookup`](crate::modules::ModuleMap::lookup), in a file that
+//! new entries are appended to.
+//!
+//! ```rust
+//! use tracefp::cache::SymbolCache;
+//!
+//! let path = std::env::temp_dir().join("tracefp-doc.cache");
+//! # let _ = std::fs::remove_file(&path);
+//! let mut cache = SymbolCache::open(&path).unwrap();
+//! let modules = tracefp::modules::ModuleMap::snapshot();
+//! let mut symbols = None;
+//! tracefp::trace(|pc| {
+//!     if let Some((module, offset)) = modules.lookup(pc) {
+//!         if cache.get(&module.build_id, offset).is_none() {
+//!             // Only read the symbols on a miss.
+//!             let symbols = symbols.get_or_insert_with(tracefp::symbols::SymbolTable::load);
+//!             if let Some(symbol) = symbols.resolve_symbol(pc) {
+//!                 cache.insert(&module.build_id, offset, &symbol.name).unwrap();
+//!             }
+//!         }
+//!         println!("{:#x} {:?}", pc, cache.get(&module.build_id, offset));
+//!     }
+//!     true
+//! });
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+//!
+//! Modules without a build-id cannot be told apart across builds and should
+//! not be cached.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::report::{read_varint, write_varint};
+
+// The header of the file, followed by a version byte.
+const MAGIC: &[u8; 4] = b"TFPC";
+const VERSION: u8 = 1;
+
+// A build-id and an offset in the module.
+type Key = (Vec<u8>, u64);
+
+/// Symbol names by build-id and offset, backed by a file.
+#[derive(Debug)]
+pub struct SymbolCache {
+    entries: HashMap<Key, String>,
+    file: File,
+}
+
+impl SymbolCache {
+    /// Opens the cache at `path`, creating it if it does not exist.
+    ///
+    /// A truncated last entry, e.g. from a crash while it was written, is
+    /// ignored and overwritten. Fails with [`io::ErrorKind::InvalidData`] if
+    /// the file is not a cache of this version.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        if data.is_empty() {
+            file.write_all(MAGIC)?;
+            file.write_all(&[VERSION])?;
+            data.extend_from_slice(MAGIC);
+            data.push(VERSION);
+        }
+        if data.len() < 5 || data[..4] != *MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a tracefp symbol cache"));
+        }
+        if data[4] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported version"));
+        }
+        let mut entries = HashMap::new();
+        let mut bytes = data[5..].iter().map(|v| Ok(*v));
+        let mut end = 5;
+        while let Ok(Some((key, name))) = read_entry(&mut bytes) {
+            end = data.len() - bytes.len();
+            entries.insert(key, name);
+        }
+        file.set_len(end as u64)?;
+        file.seek(SeekFrom::Start(end as u64))?;
+        Ok(Self { entries, file })
+    }
+
+    /// The name cached for `offset` in the module with `build_id`.
+    pub fn get(&self, build_id: &[u8], offset: u64) -> Option<&str> {
+        self.entries.get(&(build_id.to_vec(), offset)).map(String::as_str)
+    }
+
+    /// Caches `name` for `offset` in the module with `build_id`, appending it
+    /// to the file unless it is already known.
+    pub fn insert(&mut self, build_id: &[u8], offset: u64, name: &str) -> io::Result<()> {
+        let key = (build_id.to_vec(), offset);
+        if self.entries.get(&key).is_some_and(|v| v == name) {
+            return Ok(());
+        }
+        let mut buffer = vec![];
+        write_varint(&mut buffer, build_id.len() as u64);
+        buffer.extend_from_slice(build_id);
+        write_varint(&mut buffer, offset);
+        write_varint(&mut buffer, name.len() as u64);
+        buffer.extend_from_slice(name.as_bytes());
+        // Appends, since the file is positioned at its end.
+        self.file.write_all(&buffer)?;
+        self.entries.insert(key, name.to_string());
+        Ok(())
+    }
+
+    /// The number of cached names.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no names are cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// Read an entry, `None` at the end of the input.
+fn read_entry<I>(bytes: &mut I) -> io::Result<Option<(Key, String)>>
+where
+    I: Iterator<Item = io::Result<u8>>,
+{
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated entry");
+    let Some(len) = read_varint(bytes)? else {
+        return Ok(None);
+    };
+    let build_id = read_bytes(bytes, len)?;
+    let offset = read_varint(bytes)?.ok_or_else(truncated)?;
+    let len = read_varint(bytes)?.ok_or_else(truncated)?;
+    let name = String::from_utf8(read_bytes(bytes, len)?).map_err(|_| truncated())?;
+    Ok(Some(((build_id, offset), name)))
+}
+
+fn read_bytes<I: Iterator<Item = io::Result<u8>>>(bytes: &mut I, len: u64) -> io::Result<Vec<u8>> {
+    let data = bytes.take(len as usize).collect::<io::Result<Vec<_>>>()?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated entry"));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_cache() {
+        let path = std::env::temp_dir().join(format!("tracefp-test-{}.cache", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut cache = SymbolCache::open(&path).unwrap();
+        assert!(cache.is_empty());
+        cache.insert(b"\x01\x02", 0x1234, "main").unwrap();
+        cache.insert(b"\x01\x02", 0x1234, "main").unwrap();
+        cache.insert(b"\x03", 0x1234, "other").unwrap();
+        assert_eq!(cache.get(b"\x01\x02", 0x1234), Some("main"));
+        assert_eq!(cache.get(b"\x01\x02", 0x1235), None);
+        drop(cache);
+
+        // A torn write is dropped, the entries before it survive.
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[2, 0xff]);
+        std::fs::write(&path, &data).unwrap();
+        let mut cache = SymbolCache::open(&path).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(b"\x03", 0x1234), Some("other"));
+        cache.insert(b"", 7, "x").unwrap();
+        drop(cache);
+        assert_eq!(SymbolCache::open(&path).unwrap().get(b"", 7), Some("x"));
+
+        std::fs::write(&path, b"TFPS\x01").unwrap();
+        assert!(SymbolCache::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
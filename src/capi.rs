@@ -0,0 +1,60 @@
+//! A C interface, enabled by the `capi` feature.
+//!
+//! The functions are declared in `include/tracefp.h`. To link tracefp into a
+//! C or C++ program, build it as a static or dynamic library:
+//!
+//! ```shell
+//! cargo rustc --release --features capi --crate-type staticlib
+//! ```
+
+use std::ffi::{c_int, c_void};
+
+/// Called with every PC of a call-stack and the `user_data` given to the
+/// tracing function. Returns nonzero to continue with the next frame.
+pub type TracefpCallback = unsafe extern "C" fn(pc: u64, user_data: *mut c_void) -> c_int;
+
+/// Passes all active PCs of the current call-stack into `callback`, like
+/// [`trace`](crate::trace).
+///
+/// # Safety
+///
+/// `callback` must be safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn tracefp_trace(callback: TracefpCallback, user_data: *mut c_void) {
+    crate::trace(|pc| callback(pc, user_data) != 0);
+}
+
+/// Passes all active PCs of the call-stack interrupted by a signal into
+/// `callback`, like [`trace_from_ucontext`](crate::trace_from_ucontext).
+/// Async-signal-safe if `callback` is.
+///
+/// # Safety
+///
+/// `ucontext` must be the third argument of a `SA_SIGINFO` signal handler,
+/// and `callback` must be safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn tracefp_trace_from_ucontext(
+    ucontext: *mut c_void,
+    callback: TracefpCallback,
+    user_data: *mut c_void,
+) {
+    crate::trace_from_ucontext(ucontext, |pc| callback(pc, user_data) != 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn collect(pc: u64, user_data: *mut c_void) -> c_int {
+        let pcs = &mut *(user_data as *mut Vec<u64>);
+        pcs.push(pc);
+        (pcs.len() < 2) as c_int
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut pcs: Vec<u64> = vec![];
+        unsafe { tracefp_trace(collect, &mut pcs as *mut Vec<u64> as *mut c_void) };
+        assert_eq!(pcs.len(), 2);
+    }
+}
@@ -0,0 +1,101 @@
+//! An API shaped like the unwinding half of the `backtrace` crate.
+//!
+//! Code written against `backtrace::trace` and `backtrace::Frame` switches to
+//! frame-pointer unwinding by importing this module instead:
+//!
+//! ```rust
+//! use tracefp::compat as backtrace;
+//!
+//! backtrace::trace(|frame| {
+//!     println!("{:?}", frame.ip());
+//!     true
+//! });
+//! ```
+//!
+//! Unlike the `backtrace` crate, tracefp does not need a global lock to
+//! unwind, so [`trace`] and [`trace_unsynchronized`] are the same.
+
+use core::ffi::c_void;
+
+/// A frame passed to the closure of [`trace`], like `backtrace::Frame`.
+#[derive(Debug, Copy, Clone)]
+pub struct Frame {
+    frame: crate::Frame,
+}
+
+impl Frame {
+    /// The instruction pointer of the frame: the interrupted instruction for
+    /// the topmost frame, the return address for all others, like the
+    /// `backtrace` crate reports it.
+    pub fn ip(&self) -> *mut c_void {
+        (self.frame.pc + self.frame.adjusted as u64) as usize as *mut c_void
+    }
+
+    /// The stack pointer of the frame, see [`Frame::sp`](crate::Frame::sp).
+    pub fn sp(&self) -> *mut c_void {
+        self.frame.sp as usize as *mut c_void
+    }
+
+    /// The address of the function of the frame. Frame records do not tell
+    /// where a function starts, so this is [`ip`](Frame::ip), which the
+    /// `backtrace` crate falls back to as well.
+    pub fn symbol_address(&self) -> *mut c_void {
+        self.ip()
+    }
+
+    /// The base address of the module of the frame. Always `None`, see
+    /// [`ModuleMap`](crate::modules::ModuleMap) to find it.
+    pub fn module_base_address(&self) -> Option<*mut c_void> {
+        None
+    }
+
+    /// The frame as reported by [`trace_frames`](crate::trace_frames).
+    pub fn inner(&self) -> &crate::Frame {
+        &self.frame
+    }
+}
+
+/// Inspects the current call-stack like `backtrace::trace`, passing every
+/// frame into the closure until it returns `false`.
+pub fn trace<F: FnMut(&Frame) -> bool>(mut cb: F) {
+    crate::trace_frames(|frame| cb(&Frame { frame: *frame }));
+}
+
+/// Same as [`trace`], since no synchronization is needed. Only `unsafe` to
+/// match the signature of `backtrace::trace_unsynchronized`.
+///
+/// # Safety
+///
+/// Always safe to call.
+pub unsafe fn trace_unsynchronized<F: FnMut(&Frame) -> bool>(cb: F) {
+    trace(cb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        let mut frames = vec![];
+        trace(|frame| {
+            frames.push(*frame);
+            true
+        });
+        assert!(frames.len() > 1);
+        assert_eq!(frames[0].ip(), frames[0].inner().pc as usize as *mut c_void);
+        for frame in &frames[1..] {
+            assert_eq!(frame.ip() as u64, frame.inner().pc + 1);
+            assert_eq!(frame.symbol_address(), frame.ip());
+        }
+
+        let mut n = 0;
+        unsafe {
+            trace_unsynchronized(|_| {
+                n += 1;
+                n < 2
+            })
+        };
+        assert_eq!(n, 2);
+    }
+}
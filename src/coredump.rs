@@ -0,0 +1,359 @@
+//! Unwinding of the threads in ELF core dumps on Linux.
+//!
+//! [`CoreDump`] reads the registers of every thread from the `NT_PRSTATUS`
+//! notes of a core file and serves the memory saved in its `PT_LOAD`
+//! segments as a [`MemoryReader`], so the stacks of a crashed process can be
+//! unwound with the same frame-pointer walk as a live one.
+//!
+//! ```rust,no_run
+//! let core = tracefp::coredump::CoreDump::open("core").unwrap();
+//! for thread in core.threads() {
+//!     println!("thread {}:", thread.tid);
+//!     core.trace(thread, |pc| {
+//!         println!("  {:#x}", pc);
+//!         true
+//!     });
+//! }
+//! ```
+//!
+//! Only core dumps of the architecture tracefp is compiled for are supported.
+
+use std::io;
+use std::path::Path;
+
+use crate::{MemoryReader, Registers, TraceOutcome};
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const ET_CORE: u16 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+#[cfg(target_arch = "x86_64")]
+const EM_NATIVE: u16 = 62;
+#[cfg(target_arch = "x86")]
+const EM_NATIVE: u16 = 3;
+#[cfg(target_arch = "aarch64")]
+const EM_NATIVE: u16 = 183;
+#[cfg(target_arch = "arm")]
+const EM_NATIVE: u16 = 40;
+#[cfg(target_arch = "riscv64")]
+const EM_NATIVE: u16 = 243;
+#[cfg(target_arch = "loongarch64")]
+const EM_NATIVE: u16 = 258;
+
+// The size of a word of the native ELF class, which is also the size of
+// `long` in the notes.
+const WORD: usize = std::mem::size_of::<libc::c_ulong>();
+
+// The offsets of `pr_pid` and `pr_reg` in `struct elf_prstatus`.
+#[cfg(target_pointer_width = "64")]
+const PR_PID: usize = 32;
+#[cfg(target_pointer_width = "64")]
+const PR_REG: usize = 112;
+#[cfg(target_pointer_width = "32")]
+const PR_PID: usize = 24;
+#[cfg(target_pointer_width = "32")]
+const PR_REG: usize = 72;
+
+/// A thread saved in a core dump.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CoreThread {
+    /// The id of the thread.
+    pub tid: i32,
+    /// The registers of the thread at the time of the dump.
+    pub registers: Registers,
+}
+
+// A part of the address space saved in the core file.
+#[derive(Debug, Copy, Clone)]
+struct Segment {
+    address: u64,
+    offset: usize,
+    size: usize,
+}
+
+/// An ELF core dump loaded into memory.
+#[derive(Debug, Clone)]
+pub struct CoreDump {
+    data: Vec<u8>,
+    segments: Vec<Segment>,
+    threads: Vec<CoreThread>,
+}
+
+impl CoreDump {
+    /// Reads and parses the core file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Parses the contents of a core file.
+    pub fn parse(data: Vec<u8>) -> io::Result<Self> {
+        let mut core = Self {
+            data,
+            segments: vec![],
+            threads: vec![],
+        };
+        let ident = core.bytes(0, 16).ok_or_else(|| invalid("truncated ELF header"))?;
+        if ident[..4] != *b"\x7fELF" {
+            return Err(invalid("not an ELF file"));
+        }
+        if ident[4] as usize * 4 != WORD || ident[5] != 1 {
+            return Err(invalid("unsupported ELF class or byte order"));
+        }
+        if core.u16(16)? != ET_CORE {
+            return Err(invalid("not a core file"));
+        }
+        if core.u16(18)? != EM_NATIVE {
+            return Err(invalid("core file of another architecture"));
+        }
+        let (phoff, phentsize, phnum) = match WORD {
+            8 => (core.word(32)?, core.u16(54)?, core.u16(56)?),
+            _ => (core.word(28)?, core.u16(42)?, core.u16(44)?),
+        };
+        for n in 0..phnum as usize {
+            // Both factors are 16-bit, so the product does not overflow.
+            let phdr = add(phoff, n * phentsize as usize)?;
+            let field = |n| core.word(add(phdr, n)?);
+            // p_offset, p_vaddr and p_filesz.
+            let (offset, address, size) = match WORD {
+                8 => (field(8)?, field(16)?, field(32)?),
+                _ => (field(4)?, field(8)?, field(16)?),
+            };
+            core.bytes(offset, size).ok_or_else(|| invalid("truncated segment"))?;
+            match core.u32(phdr)? {
+                PT_LOAD => core.segments.push(Segment {
+                    address: address as u64,
+                    offset,
+                    size,
+                }),
+                PT_NOTE => core.parse_notes(offset, size)?,
+                _ => {}
+            }
+        }
+        Ok(core)
+    }
+
+    /// The threads saved in the core dump, the one that caused the dump
+    /// first.
+    pub fn threads(&self) -> &[CoreThread] {
+        &self.threads
+    }
+
+    /// Inspects the call-stack of `thread`, passing all active PCs into the
+    /// closure provided, like [`trace`](crate::trace).
+    pub fn trace<F>(&self, thread: &CoreThread, f: F) -> TraceOutcome
+    where
+        F: FnMut(u64) -> bool,
+    {
+        crate::trace_with_reader(&thread.registers, self, f)
+    }
+
+    fn parse_notes(&mut self, offset: usize, size: usize) -> io::Result<()> {
+        // The segment is in the file, so its end does not overflow.
+        let end = offset + size;
+        let mut note = offset;
+        while note.checked_add(12).is_some_and(|v| v <= end) {
+            let namesz = self.u32(note)? as usize;
+            let descsz = self.u32(note + 4)? as usize;
+            let desc = add(note + 12, align4(namesz))?;
+            if self.u32(note + 8)? == NT_PRSTATUS {
+                self.bytes(desc, descsz).ok_or_else(|| invalid("truncated file"))?;
+                self.parse_prstatus(desc, descsz)?;
+            }
+            note = add(desc, align4(descsz))?;
+        }
+        Ok(())
+    }
+
+    fn parse_prstatus(&mut self, desc: usize, size: usize) -> io::Result<()> {
+        if size < PR_REG {
+            return Err(invalid("truncated NT_PRSTATUS note"));
+        }
+        let mut regs = [0 as libc::c_ulong; 64];
+        let count = ((size - PR_REG) / WORD).min(regs.len());
+        for (n, reg) in regs.iter_mut().enumerate().take(count) {
+            *reg = self.word(desc + PR_REG + n * WORD)? as libc::c_ulong;
+        }
+        self.threads.push(CoreThread {
+            tid: self.u32(desc + PR_PID)? as i32,
+            registers: crate::remote::from_user_regs(&regs),
+        });
+        Ok(())
+    }
+
+    fn bytes(&self, offset: usize, size: usize) -> Option<&[u8]> {
+        self.data.get(offset..offset.checked_add(size)?)
+    }
+
+    fn u16(&self, offset: usize) -> io::Result<u16> {
+        let bytes = self.bytes(offset, 2).ok_or_else(|| invalid("truncated file"))?;
+        Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        let bytes = self.bytes(offset, 4).ok_or_else(|| invalid("truncated file"))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // Read a word of the native ELF class.
+    fn word(&self, offset: usize) -> io::Result<usize> {
+        let bytes = self.bytes(offset, WORD).ok_or_else(|| invalid("truncated file"))?;
+        let mut word = [0u8; 8];
+        word[..WORD].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(word) as usize)
+    }
+
+    // The saved memory at `address`, if all `size` bytes are in the file.
+    fn memory(&self, address: u64, size: usize) -> Option<&[u8]> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|s| address >= s.address && address - s.address < s.size as u64)?;
+        let start = (address - segment.address) as usize;
+        if start + size > segment.size {
+            return None;
+        }
+        self.bytes(segment.offset + start, size)
+    }
+}
+
+impl MemoryReader for CoreDump {
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        Some(u64::from_le_bytes(self.memory(address, 8)?.try_into().ok()?))
+    }
+
+    fn read_u32(&self, address: u64) -> Option<u32> {
+        Some(u32::from_le_bytes(self.memory(address, 4)?.try_into().ok()?))
+    }
+}
+
+// Saturates, the offsets computed from the result are checked.
+#[inline]
+fn align4(n: usize) -> usize {
+    n.saturating_add(3) & !3
+}
+
+// The offset `n` bytes after `base`, both read from the file, which is out of
+// the file if it overflows.
+fn add(base: usize, n: usize) -> io::Result<usize> {
+    base.checked_add(n).ok_or_else(|| invalid("truncated file"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RECORD_OFFSET;
+
+    fn push_word(data: &mut Vec<u8>, value: u64) {
+        data.extend_from_slice(&value.to_le_bytes()[..WORD]);
+    }
+
+    // Build a core file with one thread, all of whose registers are
+    // `value`, and one segment of memory at `address`.
+    fn build_core(value: u64, address: u64, memory: &[u64]) -> Vec<u8> {
+        let (ehsize, phentsize) = if WORD == 8 { (64, 56) } else { (52, 32) };
+        let mut desc = vec![0u8; PR_REG];
+        desc[PR_PID..PR_PID + 4].copy_from_slice(&42u32.to_le_bytes());
+        for _ in 0..34 {
+            push_word(&mut desc, value);
+        }
+        let mut note = vec![];
+        note.extend_from_slice(&5u32.to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+        note.extend_from_slice(b"CORE\0\0\0\0");
+        note.extend_from_slice(&desc);
+        let mut load = vec![];
+        for value in memory {
+            push_word(&mut load, *value);
+        }
+
+        let note_offset = ehsize + 2 * phentsize;
+        let load_offset = note_offset + note.len();
+        let mut data = vec![0x7f, b'E', b'L', b'F', (WORD / 4) as u8, 1, 1];
+        data.resize(16, 0);
+        data.extend_from_slice(&ET_CORE.to_le_bytes());
+        data.extend_from_slice(&EM_NATIVE.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        push_word(&mut data, 0);
+        push_word(&mut data, ehsize as u64);
+        push_word(&mut data, 0);
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(ehsize as u16).to_le_bytes());
+        data.extend_from_slice(&(phentsize as u16).to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.resize(ehsize, 0);
+        for (kind, offset, vaddr, size) in [
+            (PT_NOTE, note_offset, 0, note.len()),
+            (PT_LOAD, load_offset, address, load.len()),
+        ] {
+            data.extend_from_slice(&kind.to_le_bytes());
+            if WORD == 8 {
+                data.extend_from_slice(&0u32.to_le_bytes());
+            }
+            push_word(&mut data, offset as u64);
+            push_word(&mut data, vaddr);
+            push_word(&mut data, 0);
+            push_word(&mut data, size as u64);
+            push_word(&mut data, size as u64);
+            if WORD != 8 {
+                data.extend_from_slice(&0u32.to_le_bytes());
+            }
+            push_word(&mut data, 0);
+        }
+        assert_eq!(data.len(), note_offset);
+        data.extend_from_slice(&note);
+        data.extend_from_slice(&load);
+        data
+    }
+
+    #[test]
+    fn test_core_dump() {
+        // A single frame record at 0x10000, called from 0x2000.
+        let fp = 0x10000 + RECORD_OFFSET;
+        let core = CoreDump::parse(build_core(fp, 0x10000, &[0, 0x2000, 0, 0])).unwrap();
+        assert_eq!(core.threads().len(), 1);
+        let thread = core.threads()[0];
+        assert_eq!(thread.tid, 42);
+        assert_eq!(thread.registers.fp, fp);
+        assert_eq!(core.read_u64(0x10000 + WORD as u64), Some(0x2000));
+        assert_eq!(core.read_u64(0x10000 + 4 * WORD as u64), None);
+
+        let mut pcs = vec![];
+        core.trace(&thread, |pc| {
+            pcs.push(pc);
+            true
+        });
+        assert_eq!(pcs, [fp, 0x1fff]);
+
+        assert!(CoreDump::parse(b"\x7fELF".to_vec()).is_err());
+        assert!(CoreDump::parse(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_core_dump() {
+        let data = build_core(0, 0x10000, &[0; 4]);
+        let (phoff, phnum) = if WORD == 8 { (32, 56) } else { (28, 44) };
+        let note = if WORD == 8 { 64 + 2 * 56 } else { 52 + 2 * 32 };
+        let cases: [(usize, &[u8]); 5] = [
+            // e_phoff
+            (phoff, &[0xff; WORD]),
+            (phoff, &(usize::MAX - 8).to_le_bytes()[..WORD]),
+            // e_phnum
+            (phnum, &[0xff; 2]),
+            // namesz and descsz of the note
+            (note, &[0xff; 4]),
+            (note + 4, &[0xf0, 0xff, 0xff, 0xff]),
+        ];
+        for (offset, bytes) in cases {
+            let mut data = data.clone();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            let err = CoreDump::parse(data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
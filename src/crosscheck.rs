@@ -0,0 +1,163 @@
+//! Validation of the frame-pointer walk against DWARF unwinding.
+//!
+//! Before trusting frame-pointer stacks of a binary in production, it is
+//! worth knowing whether all of its code maintains frame pointers.
+//! [`cross_check`] unwinds the current stack both with tracefp and with the
+//! DWARF-based unwinder of the `backtrace` crate and reports where they
+//! diverge. This is a development tool, enabled by the `crosscheck`
+//! feature: DWARF unwinding is slow, allocates and is not signal-safe.
+//!
+//! ```rust
+//! let check = tracefp::crosscheck::cross_check();
+//! if !check.matches() {
+//!     eprintln!("{}", check);
+//! }
+//! ```
+
+use std::fmt;
+
+/// The first frame at which the two unwinders disagree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The index of the frame in both stacks.
+    pub index: usize,
+    /// The return address found by the frame-pointer walk, if it got that
+    /// far.
+    pub fp: Option<u64>,
+    /// The return address found by DWARF unwinding, if it got that far.
+    pub dwarf: Option<u64>,
+}
+
+/// The result of [`cross_check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossCheck {
+    /// The return addresses found by the frame-pointer walk, innermost frame
+    /// first, starting at the caller of [`cross_check`].
+    pub fp: Vec<u64>,
+    /// The return addresses found by DWARF unwinding, starting at the same
+    /// frame.
+    pub dwarf: Vec<u64>,
+    /// Where the stacks diverge, `None` if they are identical.
+    pub divergence: Option<Divergence>,
+}
+
+impl CrossCheck {
+    /// Whether both unwinders found the same stack.
+    pub fn matches(&self) -> bool {
+        self.divergence.is_none()
+    }
+
+    fn new(fp: Vec<u64>, dwarf: Vec<u64>) -> Self {
+        // Both walks start inside their own implementation, the stacks are
+        // aligned at the first return address they have in common.
+        let start = fp
+            .iter()
+            .enumerate()
+            .find_map(|(n, pc)| Some((n, dwarf.iter().position(|v| v == pc)?)));
+        let (fp, dwarf) = match start {
+            Some((m, n)) => (fp[m..].to_vec(), dwarf[n..].to_vec()),
+            None => (fp, dwarf),
+        };
+        let divergence = (0..fp.len().max(dwarf.len()))
+            .map(|index| Divergence {
+                index,
+                fp: fp.get(index).copied(),
+                dwarf: dwarf.get(index).copied(),
+            })
+            .find(|d| d.fp != d.dwarf);
+        Self { fp, dwarf, divergence }
+    }
+}
+
+/// Lists both stacks side by side with symbol names, marking the frames from
+/// the divergence on.
+impl fmt::Display for CrossCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diverged = self.divergence.map_or(usize::MAX, |d| d.index);
+        writeln!(f, "   {:<18} {:<18} function", "frame pointers", "dwarf")?;
+        for index in 0..self.fp.len().max(self.dwarf.len()) {
+            let fp = self.fp.get(index).copied();
+            let dwarf = self.dwarf.get(index).copied();
+            let mark = if index >= diverged { '!' } else { ' ' };
+            let column = |pc: Option<u64>| pc.map_or_else(|| "-".to_string(), |v| format!("{:#x}", v));
+            writeln!(
+                f,
+                "{}  {:<18} {:<18} {}",
+                mark,
+                column(fp),
+                column(dwarf),
+                fp.or(dwarf).map(name).unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Unwinds the current stack with both unwinders and compares the results.
+#[inline(never)]
+pub fn cross_check() -> CrossCheck {
+    // Return addresses, unlike the PCs reported by `trace`.
+    let mut fp = vec![];
+    crate::trace_frames(|frame| {
+        fp.push(frame.pc + frame.adjusted as u64);
+        true
+    });
+    let mut dwarf = vec![];
+    backtrace::trace(|frame| {
+        // The outermost frame may have a return address of 0.
+        if !frame.ip().is_null() {
+            dwarf.push(frame.ip() as usize as u64);
+        }
+        true
+    });
+    CrossCheck::new(fp, dwarf)
+}
+
+// The name of the function containing the return address `pc`.
+fn name(pc: u64) -> String {
+    let mut name = String::new();
+    backtrace::resolve(pc.saturating_sub(1) as usize as *mut std::ffi::c_void, |symbol| {
+        if name.is_empty() {
+            if let Some(v) = symbol.name() {
+                name = format!("{:#}", v);
+            }
+        }
+    });
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn check() -> CrossCheck {
+        std::hint::black_box(cross_check())
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let result = check();
+        // The frames of this crate agree at least, std may have been built
+        // without frame pointers.
+        assert!(result.divergence.is_none_or(|d| d.index >= 2), "{}", result);
+        assert!(name(result.fp[0]).ends_with("tests::check"), "{}", result);
+        assert!(format!("{}", result).contains("test_cross_check"));
+    }
+
+    #[test]
+    fn test_divergence() {
+        let check = CrossCheck::new(vec![9, 1, 2, 3], vec![8, 8, 1, 2, 4, 5]);
+        assert_eq!(check.fp, [1, 2, 3]);
+        assert_eq!(check.dwarf, [1, 2, 4, 5]);
+        assert_eq!(
+            check.divergence,
+            Some(Divergence {
+                index: 2,
+                fp: Some(3),
+                dwarf: Some(4)
+            })
+        );
+        assert!(CrossCheck::new(vec![1, 2], vec![0, 1, 2]).matches());
+    }
+}
@@ -0,0 +1,851 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory::{LocalMemory, MemoryReader};
+use crate::options::StackBoundsMode;
+use crate::{
+    canonicalize, check_fp, is_aligned_pc, jit, sigtramp, split_thumb, strip_pac, Frame, Registers, StackBounds,
+    TraceOptions, FP_ALIGN, RECORD_OFFSET, WORD,
+};
+
+// The absolute number of steps a hardened cursor takes before giving up.
+//
+// This is far deeper than any sane call-stack and only exists to bound the
+// time spent in a signal handler when the chain is corrupted in a way that
+// still passes all other checks.
+const FRAME_LIMIT: usize = 65536;
+
+/// Why an [`UnwindCursor`] could not step any further.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StopReason {
+    /// The outermost frame was reached: the frame pointer or the return
+    /// address is 0.
+    EndOfChain,
+    /// The frame pointer or the return address loaded from the frame record
+    /// cannot be valid, e.g. it is misaligned, not canonical or points outside
+    /// of the stack bounds, or of user-space if they are not known.
+    InvalidFramePointer,
+    /// The frame record could not be read.
+    MemoryAccessDenied,
+    /// The frame pointer did not move towards the base of the stack, following
+    /// it would revisit frames that were already unwound.
+    CycleDetected,
+    /// The absolute frame count limit of hardened mode was reached.
+    FrameLimitReached,
+    /// The next frame record lies beyond
+    /// [`TraceOptions::max_stack_bytes`] from the topmost stack pointer.
+    StackLimitReached,
+}
+
+/// Numbers of frame pointers that were not followed because they cannot
+/// point to a frame record, by reason, see [`fp_rejections`].
+///
+/// Every rejection ends a walk with [`StopReason::InvalidFramePointer`]. A
+/// frame pointer of 0 is the regular end of the chain and not counted, nor
+/// are frame pointers outside of known stack bounds, which replace these
+/// checks.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FpRejections {
+    /// Frame pointers below 4096, e.g. a small integer left in the register
+    /// by code without frame pointers. The first page is never mapped.
+    pub low: u64,
+    /// Values that are no address at all, e.g. not canonical on x86_64 or
+    /// wider than 32 bits on 32-bit architectures.
+    pub non_canonical: u64,
+    /// Frame pointers beyond the end of user-space, e.g. into the kernel half
+    /// of the address space.
+    pub kernel: u64,
+    /// Frame pointers the predicate of [`TraceOptions::fp_check`] returned
+    /// `false` for.
+    pub custom: u64,
+}
+
+// Why a frame pointer was rejected, indexing `REJECTIONS`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum FpRejection {
+    Low,
+    NonCanonical,
+    Kernel,
+    Custom,
+}
+
+static REJECTIONS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+/// Returns the numbers of frame pointers rejected by the walks of all threads
+/// since the start of the process or the last [`reset_fp_rejections`].
+///
+/// A growing count hints at code built without frame pointers, whose stacks
+/// end early.
+pub fn fp_rejections() -> FpRejections {
+    let count = |reason: FpRejection| REJECTIONS[reason as usize].load(Ordering::Relaxed);
+    FpRejections {
+        low: count(FpRejection::Low),
+        non_canonical: count(FpRejection::NonCanonical),
+        kernel: count(FpRejection::Kernel),
+        custom: count(FpRejection::Custom),
+    }
+}
+
+/// Resets the counts returned by [`fp_rejections`] to 0.
+pub fn reset_fp_rejections() {
+    for count in &REJECTIONS {
+        count.store(0, Ordering::Relaxed);
+    }
+}
+
+/// How a walk of the `trace` family ended.
+///
+/// Everything but [`Completed`](TraceOutcome::Completed) and
+/// [`CallbackStopped`](TraceOutcome::CallbackStopped) means that the reported
+/// stack is missing its outer frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceOutcome {
+    /// The outermost frame was reached.
+    Completed,
+    /// The closure returned `false`.
+    CallbackStopped,
+    /// There are more frames than [`TraceOptions::max_depth`].
+    MaxDepthReached,
+    /// See [`StopReason::InvalidFramePointer`]. Also returned if the topmost
+    /// PC is not valid.
+    InvalidFramePointer,
+    /// See [`StopReason::MemoryAccessDenied`].
+    MemoryAccessDenied,
+    /// See [`StopReason::CycleDetected`].
+    CycleDetected,
+    /// See [`StopReason::FrameLimitReached`].
+    FrameLimitReached,
+    /// See [`StopReason::StackLimitReached`].
+    StackLimitReached,
+    /// The `ucontext` is null and there is nothing to unwind.
+    NullUcontext,
+}
+
+impl TraceOutcome {
+    /// Whether the walk reached the outermost frame, or was stopped by the
+    /// closure.
+    pub fn is_complete(&self) -> bool {
+        matches!(self, Self::Completed | Self::CallbackStopped)
+    }
+}
+
+impl From<StopReason> for TraceOutcome {
+    fn from(reason: StopReason) -> Self {
+        match reason {
+            StopReason::EndOfChain => Self::Completed,
+            StopReason::InvalidFramePointer => Self::InvalidFramePointer,
+            StopReason::MemoryAccessDenied => Self::MemoryAccessDenied,
+            StopReason::CycleDetected => Self::CycleDetected,
+            StopReason::FrameLimitReached => Self::FrameLimitReached,
+            StopReason::StackLimitReached => Self::StackLimitReached,
+        }
+    }
+}
+
+/// A step-by-step unwinder over a frame-pointer chain.
+///
+/// A cursor always points at one frame of the call-stack, starting with the
+/// topmost one. [`step`](UnwindCursor::step) moves it to the caller, which lets
+/// the caller interleave its own logic between steps, or stop and resume the
+/// unwinding at any time.
+///
+/// ```rust
+/// use tracefp::UnwindCursor;
+///
+/// # #[cfg(target_os = "linux")]
+/// # fn example() {
+/// let mut ucontext: libc::ucontext_t = unsafe { std::mem::zeroed() };
+/// assert_eq!(unsafe { libc::getcontext(&mut ucontext) }, 0);
+/// let ucontext = &mut ucontext as *mut libc::ucontext_t as *mut libc::c_void;
+///
+/// let mut cursor = UnwindCursor::new_from_ucontext(ucontext).unwrap();
+/// loop {
+///     println!("pc: {:#x}, fp: {:#x}", cursor.pc(), cursor.fp());
+///     if !cursor.step() {
+///         break;
+///     }
+/// }
+/// # }
+/// # #[cfg(target_os = "linux")]
+/// # example();
+/// ```
+#[derive(Debug, Clone)]
+pub struct UnwindCursor<R = LocalMemory> {
+    frame: Frame,
+    options: TraceOptions,
+    // Frame records must lie within these bounds, if known.
+    bounds: Option<StackBounds>,
+    // The stack the chain may continue on once it leaves `bounds`: the thread
+    // stack when unwinding from the alternate signal stack.
+    resume: Option<StackBounds>,
+    // The address of the last frame record that was read, 0 before the first
+    // step.
+    record: u64,
+    // The link register of the topmost frame until the first step, 0 if it
+    // is not to be reported.
+    lr: u64,
+    // The stack pointer of the topmost frame, or the first frame record on
+    // the `resume` stack once the chain moved there.
+    top_sp: u64,
+    // The number of bytes spanned on the stack left for `resume`.
+    spanned: u64,
+    reader: R,
+    steps: usize,
+    stop: Option<StopReason>,
+}
+
+#[cfg(feature = "std")]
+impl UnwindCursor {
+    /// Creates a cursor pointing at the frame whose registers are saved in
+    /// `ucontext`.
+    ///
+    /// Returns `None` if `ucontext` is null or holds no valid PC.
+    pub fn new_from_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+        Self::new_from_ucontext_with_options(ucontext, &TraceOptions::default())
+    }
+
+    /// Same as [`new_from_ucontext`](UnwindCursor::new_from_ucontext), but the
+    /// cursor steps according to `options`.
+    ///
+    /// `max_depth` and `skip` are not applied by the cursor itself, the caller
+    /// decides how many steps to take.
+    pub fn new_from_ucontext_with_options(ucontext: *mut libc::c_void, options: &TraceOptions) -> Option<Self> {
+        Self::new_local(&Registers::from_ucontext(ucontext)?, options)
+    }
+
+    // Creates a cursor over the memory of the current process.
+    pub(crate) fn new_local(registers: &Registers, options: &TraceOptions) -> Option<Self> {
+        let (bounds, resume) = match options.stack_bounds {
+            StackBoundsMode::Auto => StackBounds::find(registers.sp).unzip(),
+            StackBoundsMode::Fixed(bounds) => (Some(bounds), None),
+            StackBoundsMode::Disabled => (None, None),
+        };
+        let bounds = bounds.map(|b| clip(b, registers.sp));
+        let mut cursor = Self::new(registers, LocalMemory::with_stack(bounds), options, bounds)?;
+        cursor.resume = resume.flatten();
+        Some(cursor)
+    }
+
+    // Creates a cursor from raw register values, for tests with synthetic
+    // frame records.
+    #[cfg(test)]
+    fn new_from_registers(pc: u64, fp: u64, sp: u64) -> Self {
+        let registers = Registers::new(pc, fp, sp, 0);
+        Self::new(&registers, LocalMemory::new(), &TraceOptions::default(), None).unwrap()
+    }
+}
+
+impl<R: MemoryReader> UnwindCursor<R> {
+    /// Creates a cursor pointing at the frame described by `registers`, which
+    /// reads the frame records through `reader`.
+    ///
+    /// The stack bounds are only applied if they were given explicitly with
+    /// [`TraceOptions::stack_bounds`], automatically detected bounds refer to
+    /// the current thread. Returns `None` if `registers` hold no valid PC.
+    pub fn new_with_reader(registers: &Registers, reader: R, options: &TraceOptions) -> Option<Self> {
+        let bounds = match options.stack_bounds {
+            StackBoundsMode::Fixed(bounds) => Some(clip(bounds, registers.sp)),
+            _ => None,
+        };
+        Self::new(registers, reader, options, bounds)
+    }
+
+    fn new(registers: &Registers, reader: R, options: &TraceOptions, bounds: Option<StackBounds>) -> Option<Self> {
+        let (pc, thumb) = split_thumb(canonicalize(registers.pc)?);
+        Some(Self {
+            frame: Frame {
+                pc,
+                fp: registers.fp,
+                sp: registers.sp,
+                is_top: true,
+                adjusted: false,
+                is_signal_trampoline: false,
+                jit_region: jit::find_jit_region(pc),
+                thumb,
+            },
+            options: *options,
+            bounds,
+            resume: None,
+            record: 0,
+            lr: if options.link_register { registers.lr } else { 0 },
+            top_sp: registers.sp,
+            spanned: 0,
+            reader,
+            steps: 0,
+            stop: None,
+        })
+    }
+
+    /// Moves the cursor to the caller of the current frame.
+    ///
+    /// Returns `false` and leaves the cursor where it is when there is no
+    /// further frame, [`stop_reason`](UnwindCursor::stop_reason) tells why.
+    pub fn step(&mut self) -> bool {
+        if self.stop.is_some() {
+            return false;
+        }
+        let next = self.next();
+        if let Some(frame) = self.leaf_caller(next.as_ref().ok().map(|(frame, _)| frame)) {
+            self.frame = frame;
+            self.steps += 1;
+            return true;
+        }
+        match next {
+            Ok((frame, record)) => {
+                if self.bounds.is_some_and(|b| !b.contains_range(record, 2 * WORD)) {
+                    self.spanned = self.stack_bytes() as u64;
+                    self.top_sp = record;
+                    self.bounds = self.resume.take();
+                }
+                self.frame = frame;
+                self.record = record;
+                self.steps += 1;
+                true
+            }
+            Err(reason) => {
+                self.stop = Some(reason);
+                false
+            }
+        }
+    }
+
+    /// Why the last [`step`](UnwindCursor::step) failed, `None` as long as
+    /// every step succeeded.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop
+    }
+
+    // Unwind the caller of the current frame, returning it together with the
+    // address of the frame record it was read from.
+    fn next(&self) -> Result<(Frame, u64), StopReason> {
+        if self.frame.is_signal_trampoline {
+            if let Some(frame) = self.interrupted() {
+                return Ok((frame, self.record));
+            }
+        }
+        if self.frame.fp == 0 {
+            return Err(StopReason::EndOfChain);
+        }
+        let fp = match self.check_fp(self.frame.fp) {
+            Ok(v) => v,
+            Err(rejection) => {
+                REJECTIONS[rejection as usize].fetch_add(1, Ordering::Relaxed);
+                return Err(StopReason::InvalidFramePointer);
+            }
+        };
+        let record = fp.checked_sub(RECORD_OFFSET).ok_or(StopReason::InvalidFramePointer)?;
+        let leaves = self.bounds.is_some_and(|b| !b.contains_range(record, 2 * WORD));
+        // Where the chain moves to another stack, its addresses jump.
+        let moves = leaves && self.resume.is_some_and(|b| b.contains_range(record, 2 * WORD));
+        if self.options.hardened {
+            if !record.is_multiple_of(FP_ALIGN) {
+                return Err(StopReason::InvalidFramePointer);
+            }
+            if record <= self.record && !moves {
+                return Err(StopReason::CycleDetected);
+            }
+            if self.steps >= FRAME_LIMIT {
+                return Err(StopReason::FrameLimitReached);
+            }
+        }
+        let end = record.checked_add(2 * WORD).ok_or(StopReason::InvalidFramePointer)?;
+        let spanned = match moves {
+            true => self.stack_bytes() as u64 + 2 * WORD,
+            false => self.spanned + end.saturating_sub(self.top_sp),
+        };
+        if spanned > self.options.max_stack_bytes as u64 {
+            return Err(StopReason::StackLimitReached);
+        }
+        if leaves && !moves {
+            return Err(StopReason::InvalidFramePointer);
+        }
+        if self.options.fp_check.is_some_and(|check| !(check.0)(fp)) {
+            REJECTIONS[FpRejection::Custom as usize].fetch_add(1, Ordering::Relaxed);
+            return Err(StopReason::InvalidFramePointer);
+        }
+        let pc = self.read_word(record + WORD).ok_or(StopReason::MemoryAccessDenied)?;
+        let next_fp = self.read_word(record).ok_or(StopReason::MemoryAccessDenied)?;
+        let pc = canonicalize(strip_pac(pc)).ok_or(StopReason::InvalidFramePointer)?;
+        if pc == 0 {
+            return Err(StopReason::EndOfChain);
+        }
+        let (pc, thumb) = split_thumb(pc);
+        if self.options.check_pc_alignment && !is_aligned_pc(pc, thumb) {
+            return Err(StopReason::InvalidFramePointer);
+        }
+        // A trampoline is returned into without a call, the address is not
+        // right after a call instruction.
+        let is_signal_trampoline = self.options.signal_trampolines && sigtramp::is_signal_trampoline(pc, &self.reader);
+        let adjusted = self.options.adjust_pc && !is_signal_trampoline;
+        let pc = if adjusted { pc - 1 } else { pc };
+        let frame = Frame {
+            pc,
+            fp: next_fp,
+            sp: end,
+            is_top: false,
+            adjusted,
+            is_signal_trampoline,
+            jit_region: jit::find_jit_region(pc),
+            thumb,
+        };
+        Ok((frame, record))
+    }
+
+    // The address `fp` refers to, if it can possibly point to a frame record.
+    //
+    // Known stack bounds replace the check for user-space addresses, which
+    // lets e.g. a kernel unwind its own stacks, and so does the predicate of
+    // `TraceOptions::fp_check`, which runs once the bounds were checked.
+    fn check_fp(&self, fp: u64) -> Result<u64, FpRejection> {
+        let fp = canonicalize(fp).ok_or(FpRejection::NonCanonical)?;
+        if self.bounds.is_none() && self.options.fp_check.is_none() {
+            check_fp(fp)?;
+        }
+        Ok(fp)
+    }
+
+    // The frame a signal interrupted, below its signal trampoline, from the
+    // context saved in the signal frame. The interrupted function may not have
+    // pushed a frame record yet, so its frame pointer is taken from there too.
+    fn interrupted(&self) -> Option<Frame> {
+        let registers = sigtramp::interrupted(self.frame.pc, self.frame.sp, &self.reader)?;
+        let pc = canonicalize(registers.pc).filter(|pc| *pc != 0)?;
+        Some(Frame {
+            pc,
+            fp: registers.fp,
+            sp: registers.sp,
+            is_top: false,
+            adjusted: false,
+            is_signal_trampoline: false,
+            jit_region: jit::find_jit_region(pc),
+            thumb: false,
+        })
+    }
+
+    // Read a word of the stack being unwound.
+    #[cfg(target_pointer_width = "64")]
+    fn read_word(&self, address: u64) -> Option<u64> {
+        self.reader.read_u64(address)
+    }
+
+    // Read a word of the stack being unwound.
+    #[cfg(target_pointer_width = "32")]
+    fn read_word(&self, address: u64) -> Option<u64> {
+        self.reader.read_u32(address).map(u64::from)
+    }
+
+    // The caller of a leaf function from the link register, unless the frame
+    // record unwound next already holds the same return address. Only the
+    // first step looks at the link register.
+    fn leaf_caller(&mut self, next: Option<&Frame>) -> Option<Frame> {
+        let lr = core::mem::take(&mut self.lr);
+        let lr = canonicalize(strip_pac(lr)).filter(|lr| *lr != 0 && *lr != self.frame.pc)?;
+        if next.is_some_and(|next| next.pc + next.adjusted as u64 == lr) {
+            return None;
+        }
+        // A leaf function neither touches the frame pointer nor has anything
+        // of its own on the stack, so the caller shares both.
+        let adjusted = self.options.adjust_pc;
+        let pc = if adjusted { lr - 1 } else { lr };
+        Some(Frame {
+            pc,
+            fp: self.frame.fp,
+            sp: self.frame.sp,
+            is_top: false,
+            adjusted,
+            is_signal_trampoline: false,
+            jit_region: jit::find_jit_region(pc),
+            thumb: false,
+        })
+    }
+
+    /// The number of bytes of stack spanned so far, from the topmost stack
+    /// pointer up to the end of the last frame record that was read. After
+    /// the chain moved from the alternate signal stack to the thread stack,
+    /// the bytes spanned on both.
+    pub fn stack_bytes(&self) -> usize {
+        let end = match self.record {
+            0 => self.top_sp,
+            record => record + 2 * WORD,
+        };
+        (self.spanned + end.saturating_sub(self.top_sp)) as usize
+    }
+
+    /// The frame the cursor currently points at.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// The program counter of the current frame, see [`Frame::pc`].
+    pub fn pc(&self) -> u64 {
+        self.frame.pc
+    }
+
+    /// The frame pointer of the current frame.
+    pub fn fp(&self) -> u64 {
+        self.frame.fp
+    }
+
+    /// The stack pointer of the current frame, see [`Frame::sp`].
+    pub fn sp(&self) -> u64 {
+        self.frame.sp
+    }
+}
+
+// Nothing below the topmost stack pointer belongs to a live frame.
+fn clip(bounds: StackBounds, sp: u64) -> StackBounds {
+    match bounds.contains(sp) {
+        true => StackBounds::new(sp, bounds.end),
+        false => bounds,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" {
+        // Declared here because `libc::getcontext()` is not found on macOS.
+        fn getcontext(ucontext: *mut libc::c_void) -> libc::c_int;
+    }
+
+    #[test]
+    fn test_unwind_cursor() {
+        assert!(UnwindCursor::new_from_ucontext(std::ptr::null_mut()).is_none());
+
+        let mut ucontext: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        #[cfg(target_os = "macos")]
+        let mut mcontext: libc::__darwin_mcontext64 = unsafe { std::mem::zeroed() };
+        #[cfg(target_os = "macos")]
+        {
+            ucontext.uc_mcontext = &mut mcontext as *mut libc::__darwin_mcontext64;
+        }
+        let ucontext = &mut ucontext as *mut libc::ucontext_t as *mut libc::c_void;
+        assert_eq!(unsafe { getcontext(ucontext) }, 0);
+
+        let mut frames = vec![];
+        crate::trace_frames_from_ucontext(ucontext, |frame| {
+            frames.push(*frame);
+            true
+        });
+        let mut cursor = UnwindCursor::new_from_ucontext(ucontext).unwrap();
+        assert!(cursor.frame().is_top);
+        let mut stepped = vec![*cursor.frame()];
+        while cursor.step() {
+            assert_eq!(cursor.pc(), cursor.frame().pc);
+            stepped.push(*cursor.frame());
+        }
+        assert!(stepped.len() > 1);
+        assert_eq!(stepped, frames);
+
+        // The cursor stays at the last frame once the chain ends.
+        let last = *cursor.frame();
+        assert!(cursor.stop_reason().is_some());
+        assert!(!cursor.step());
+        assert_eq!(*cursor.frame(), last);
+    }
+
+    // Synthetic stack memory, aligned like a real frame record.
+    #[repr(C, align(16))]
+    struct Stack([usize; 4]);
+
+    impl Stack {
+        fn address(&self) -> u64 {
+            self as *const Stack as u64
+        }
+
+        // The frame pointer of the frame whose record starts at word `n`.
+        fn fp(&self, n: u64) -> u64 {
+            self.address() + n * WORD + RECORD_OFFSET
+        }
+    }
+
+    #[test]
+    fn test_hardened_cycle() {
+        // A frame record pointing at itself.
+        let mut stack = Stack([0, 0x1000, 0, 0]);
+        stack.0[0] = stack.fp(0) as usize;
+
+        let fp = stack.fp(0);
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        assert!(cursor.step());
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::CycleDetected));
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        cursor.options = TraceOptions::new().hardened(false);
+        for _ in 0..100 {
+            assert!(cursor.step());
+        }
+    }
+
+    #[test]
+    fn test_hardened_alignment() {
+        let stack = Stack([0; 4]);
+        let fp = stack.fp(0) + 2;
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::InvalidFramePointer));
+    }
+
+    #[test]
+    fn test_fp_rejections() {
+        let stack = Stack([0, 0x1000, 0, 0]);
+        // Other tests walk stacks concurrently, so counts only grow.
+        let step = |fp, options: TraceOptions| {
+            let before = fp_rejections();
+            let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+            cursor.options = options;
+            let stepped = cursor.step();
+            (stepped, cursor.stop_reason(), before, fp_rejections())
+        };
+        let (stepped, reason, before, after) = step(8, TraceOptions::new());
+        assert!(!stepped);
+        assert_eq!(reason, Some(StopReason::InvalidFramePointer));
+        assert!(after.low > before.low);
+        #[cfg(target_pointer_width = "64")]
+        {
+            let (_, reason, before, after) = step(0xffff_ffff_ffff_0000, TraceOptions::new());
+            assert_eq!(reason, Some(StopReason::InvalidFramePointer));
+            assert!(after.kernel > before.kernel);
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            let (_, _, before, after) = step(1 << 50, TraceOptions::new());
+            assert!(after.non_canonical > before.non_canonical);
+        }
+
+        // The predicate replaces the check.
+        let (stepped, reason, before, after) = step(stack.fp(0), TraceOptions::new().fp_check(|_| false));
+        assert!(!stepped);
+        assert_eq!(reason, Some(StopReason::InvalidFramePointer));
+        assert!(after.custom > before.custom);
+        let (stepped, ..) = step(stack.fp(0), TraceOptions::new().fp_check(|fp| fp != 0));
+        assert!(stepped);
+
+        // Also where the stack bounds are known, as for the `trace` family.
+        let before = fp_rejections();
+        let outcome = crate::trace_with_options(&TraceOptions::new().fp_check(|_| false), |_| true);
+        assert_eq!(outcome, TraceOutcome::InvalidFramePointer);
+        assert!(fp_rejections().custom > before.custom);
+        let options = TraceOptions::new().fp_check(|fp| fp != 0);
+        assert_eq!(crate::trace_with_options(&options, |_| true), TraceOutcome::Completed);
+
+        // Nothing else uses a predicate.
+        reset_fp_rejections();
+        assert_eq!(fp_rejections().custom, 0);
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        let mut stack = Stack([0, 0x1000, 0, 0x2000]);
+        stack.0[0] = stack.fp(2) as usize;
+        let fp = stack.fp(0);
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        cursor.bounds = Some(StackBounds::new(stack.address(), stack.address() + 4 * WORD));
+        assert!(cursor.step());
+        assert!(cursor.step());
+        assert_eq!(cursor.stop_reason(), None);
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        cursor.bounds = Some(StackBounds::new(stack.address(), stack.address() + 3 * WORD));
+        assert!(cursor.step());
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::InvalidFramePointer));
+    }
+
+    #[test]
+    fn test_resume_stack() {
+        // The thread stack, and above it the alternate signal stack with the
+        // frame of a handler.
+        let mut stacks = [Stack([0, 0x2000, 0, 0x3000]), Stack([0, 0x1000, 0, 0])];
+        stacks[0].0[0] = stacks[0].fp(2) as usize;
+        stacks[1].0[0] = stacks[0].fp(0) as usize;
+        let [thread, altstack] = &stacks;
+        let bounds = |stack: &Stack| StackBounds::new(stack.address(), stack.address() + 4 * WORD);
+
+        let fp = altstack.fp(0);
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, altstack.address());
+        cursor.bounds = Some(bounds(altstack));
+        // Without the thread stack the jump down looks like a cycle.
+        assert!(cursor.step());
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::CycleDetected));
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, altstack.address());
+        cursor.bounds = Some(bounds(altstack));
+        cursor.resume = Some(bounds(thread));
+        let mut pcs = vec![];
+        while cursor.step() {
+            pcs.push(cursor.pc());
+        }
+        assert_eq!(cursor.stop_reason(), Some(StopReason::EndOfChain));
+        assert_eq!(pcs, [0xfff, 0x1fff, 0x2fff]);
+        assert_eq!(cursor.stack_bytes() as u64, 6 * WORD);
+        assert_eq!(cursor.bounds, Some(bounds(thread)));
+    }
+
+    #[test]
+    fn test_check_pc_alignment() {
+        let stack = Stack([0, 0x1001, 0, 0]);
+        let fp = stack.fp(0);
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        assert!(cursor.step());
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        cursor.options = TraceOptions::new().check_pc_alignment(true);
+        let aligned = cfg!(any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm"));
+        assert_eq!(cursor.step(), aligned);
+        if !aligned {
+            assert_eq!(cursor.stop_reason(), Some(StopReason::InvalidFramePointer));
+        }
+        // The Thumb bit is not part of the address.
+        if cfg!(target_arch = "arm") {
+            assert!(cursor.frame().thumb);
+            assert_eq!(cursor.pc(), 0xfff);
+        }
+    }
+
+    #[test]
+    fn test_link_register() {
+        let stack = Stack([0, 0x2000, 0, 0]);
+        let fp = stack.fp(0);
+
+        // A leaf called from 0x3000, whose caller returns to 0x2000.
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        cursor.lr = 0x3000;
+        assert!(cursor.step());
+        assert_eq!(cursor.pc(), 0x2fff);
+        assert_eq!(cursor.fp(), fp);
+        assert!(cursor.step());
+        assert_eq!(cursor.pc(), 0x1fff);
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::EndOfChain));
+
+        // The link register duplicates the return address in the record.
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        cursor.lr = 0x2000;
+        assert!(cursor.step());
+        assert_eq!(cursor.pc(), 0x1fff);
+        assert!(!cursor.step());
+
+        // The link register duplicates the topmost PC.
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, 0, fp);
+        cursor.lr = 0x1000;
+        assert!(!cursor.step());
+
+        // The leaf's caller is known even if the chain ends immediately.
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, 0, fp);
+        cursor.lr = 0x3000;
+        assert!(cursor.step());
+        assert_eq!(cursor.pc(), 0x2fff);
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::EndOfChain));
+    }
+
+    // A stack in some other address space.
+    struct Synthetic(std::collections::HashMap<u64, u64>);
+
+    impl MemoryReader for Synthetic {
+        fn read_u64(&self, address: u64) -> Option<u64> {
+            self.0.get(&address).copied()
+        }
+
+        fn read_u32(&self, address: u64) -> Option<u32> {
+            self.read_u64(address).map(|v| v as u32)
+        }
+    }
+
+    #[test]
+    fn test_reader() {
+        let (first, second) = (0x7000, 0x7000 + 2 * WORD);
+        let reader = Synthetic(
+            [
+                (first, second + RECORD_OFFSET),
+                (first + WORD, 0x2000),
+                (second, 0),
+                (second + WORD, 0x3000),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let registers = Registers::new(0x1000, first + RECORD_OFFSET, first, 0);
+        let mut pcs = vec![];
+        let outcome = crate::trace_with_reader(&registers, &reader, |pc| {
+            pcs.push(pc);
+            true
+        });
+        assert_eq!(pcs, [0x1000, 0x1fff, 0x2fff]);
+        assert_eq!(outcome, TraceOutcome::Completed);
+
+        let mut cursor = UnwindCursor::new_with_reader(&registers, &reader, &TraceOptions::default()).unwrap();
+        while cursor.step() {}
+        assert_eq!(cursor.stop_reason(), Some(StopReason::EndOfChain));
+        assert_eq!(cursor.sp(), second + 2 * WORD);
+
+        let registers = Registers::new(0x1000, 0x9000, first, 0);
+        let mut cursor = UnwindCursor::new_with_reader(&registers, &reader, &TraceOptions::default()).unwrap();
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::MemoryAccessDenied));
+        let outcome = crate::trace_with_reader(&registers, &reader, |_| true);
+        assert_eq!(outcome, TraceOutcome::MemoryAccessDenied);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_kernel_stack() {
+        // Frame records outside of user-space are followed within known bounds.
+        let first = 0xffff_ff00_0000_7000;
+        let second = first + 2 * WORD;
+        let reader = Synthetic(
+            [(first, second + RECORD_OFFSET), (first + WORD, 0xffff_ff00_0000_2000)]
+                .into_iter()
+                .collect(),
+        );
+        let registers = Registers::new(0xffff_ff00_0000_1000, first + RECORD_OFFSET, first, 0);
+        let mut cursor = UnwindCursor::new_with_reader(&registers, &reader, &TraceOptions::default()).unwrap();
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::InvalidFramePointer));
+
+        let options = TraceOptions::new().stack_bounds(Some(StackBounds::new(first, first + 4096)));
+        let mut cursor = UnwindCursor::new_with_reader(&registers, &reader, &options).unwrap();
+        assert!(cursor.step());
+        assert_eq!(cursor.pc(), 0xffff_ff00_0000_1fff);
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::MemoryAccessDenied));
+    }
+
+    #[test]
+    fn test_max_stack_bytes() {
+        let mut stack = Stack([0, 0x1000, 0, 0x2000]);
+        stack.0[0] = stack.fp(2) as usize;
+        let fp = stack.fp(0);
+        let sp = stack.address();
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, sp);
+        cursor.options = TraceOptions::new().max_stack_bytes(3 * WORD as usize);
+        assert_eq!(cursor.stack_bytes(), 0);
+        assert!(cursor.step());
+        assert_eq!(cursor.stack_bytes(), 2 * WORD as usize);
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::StackLimitReached));
+
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, sp);
+        cursor.options = TraceOptions::new().max_stack_bytes(4 * WORD as usize);
+        assert!(cursor.step());
+        assert!(cursor.step());
+        assert_eq!(cursor.stack_bytes(), 4 * WORD as usize);
+    }
+
+    #[test]
+    fn test_end_of_chain() {
+        let stack = Stack([0; 4]);
+        let fp = stack.fp(0);
+        let mut cursor = UnwindCursor::new_from_registers(0x1000, fp, fp);
+        assert!(!cursor.step());
+        assert_eq!(cursor.stop_reason(), Some(StopReason::EndOfChain));
+    }
+}
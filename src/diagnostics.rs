@@ -0,0 +1,238 @@
+//! Detection of modules built without frame pointers.
+//!
+//! A module compiled without frame pointers (the default of most compilers
+//! with optimizations) breaks the chain in every function of it, which shows
+//! up as stacks that end after one or two frames. [`verify_frame_pointers`]
+//! looks at the prologue of every function of every loaded module and counts
+//! how many of them set up the frame pointer.
+//!
+//! ```rust
+//! for module in tracefp::diagnostics::verify_frame_pointers() {
+//!     if !module.maintains_frame_pointers() {
+//!         println!("{} was built without frame pointers", module.path.display());
+//!     }
+//! }
+//! ```
+//!
+//! This is a heuristic: leaf functions may omit the frame pointer even in
+//! builds that keep it otherwise, and functions are only found through the
+//! symbol tables, see [`symbols`](crate::symbols).
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::modules::ModuleMap;
+use crate::symbols::SymbolTable;
+
+// The number of bytes at the start of a function searched for the frame
+// pointer setup.
+const PROLOGUE: usize = 32;
+
+/// The result of [`verify_frame_pointers`] for one module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ModuleCheck {
+    /// The path the module was loaded from.
+    pub path: PathBuf,
+    /// The number of functions whose prologue was checked.
+    pub functions: usize,
+    /// The number of functions that set up the frame pointer.
+    pub with_frame_pointer: usize,
+}
+
+impl ModuleCheck {
+    /// The share of the checked functions that set up the frame pointer,
+    /// `None` if no function was checked.
+    pub fn ratio(&self) -> Option<f64> {
+        match self.functions {
+            0 => None,
+            n => Some(self.with_frame_pointer as f64 / n as f64),
+        }
+    }
+
+    /// Whether most functions of the module set up the frame pointer. True
+    /// if no function was checked.
+    pub fn maintains_frame_pointers(&self) -> bool {
+        self.ratio().is_none_or(|v| v >= 0.5)
+    }
+}
+
+/// Checks the function prologues of all modules currently loaded, in the
+/// order of [`ModuleMap::modules`]. Must not be called from a signal handler.
+///
+/// Modules whose symbols cannot be read have no functions checked.
+pub fn verify_frame_pointers() -> Vec<ModuleCheck> {
+    let modules = ModuleMap::snapshot();
+    let mut checks: Vec<_> = modules
+        .modules()
+        .iter()
+        .map(|module| ModuleCheck {
+            path: module.path.clone(),
+            functions: 0,
+            with_frame_pointer: 0,
+        })
+        .collect();
+    let indexes: HashMap<_, _> = modules
+        .modules()
+        .iter()
+        .enumerate()
+        .map(|(n, module)| (module as *const _, n))
+        .collect();
+    for symbol in SymbolTable::load().symbols() {
+        // Thumb functions have the lowest bit set.
+        #[cfg(target_arch = "arm")]
+        let (address, thumb) = (symbol.address & !1, symbol.address & 1 != 0);
+        #[cfg(not(target_arch = "arm"))]
+        let (address, thumb) = (symbol.address, false);
+        let len = match symbol.size {
+            0 => PROLOGUE,
+            size => (size as usize).min(PROLOGUE),
+        } as u64;
+        // Only read code of the executable segments.
+        let (Some((first, _)), Some((last, _))) = (modules.lookup(address), modules.lookup(address + len - 1)) else {
+            continue;
+        };
+        if !std::ptr::eq(first, last) {
+            continue;
+        }
+        let code = unsafe { std::slice::from_raw_parts(address as *const u8, len as usize) };
+        let check = &mut checks[indexes[&(first as *const _)]];
+        check.functions += 1;
+        if sets_frame_pointer(code, thumb) {
+            check.with_frame_pointer += 1;
+        }
+    }
+    checks
+}
+
+// Whether `code`, the start of a function, sets up the frame pointer:
+// `push %rbp; mov %rsp, %rbp` after an optional `endbr64`.
+#[cfg(target_arch = "x86_64")]
+fn sets_frame_pointer(code: &[u8], _thumb: bool) -> bool {
+    let code = code.strip_prefix(&[0xf3, 0x0f, 0x1e, 0xfa]).unwrap_or(code);
+    code.starts_with(&[0x55, 0x48, 0x89, 0xe5]) || code.starts_with(&[0x55, 0x48, 0x8b, 0xec])
+}
+
+// Whether `code`, the start of a function, sets up the frame pointer:
+// `push %ebp; mov %esp, %ebp` after an optional `endbr32`.
+#[cfg(target_arch = "x86")]
+fn sets_frame_pointer(code: &[u8], _thumb: bool) -> bool {
+    let code = code.strip_prefix(&[0xf3, 0x0f, 0x1e, 0xfb]).unwrap_or(code);
+    code.starts_with(&[0x55, 0x89, 0xe5]) || code.starts_with(&[0x55, 0x8b, 0xec])
+}
+
+// Whether `code`, the start of a function, sets up the frame pointer:
+// `add x29, sp, #n`, which includes `mov x29, sp`.
+#[cfg(target_arch = "aarch64")]
+fn sets_frame_pointer(code: &[u8], _thumb: bool) -> bool {
+    words(code).any(|insn| insn & 0xff8003ff == 0x910003fd)
+}
+
+// Whether `code`, the start of a function, sets up the frame pointer:
+// `addi s0, sp, n`, or its compressed form `c.addi4spn s0, sp, n`.
+#[cfg(target_arch = "riscv64")]
+fn sets_frame_pointer(code: &[u8], _thumb: bool) -> bool {
+    let mut n = 0;
+    while n + 2 <= code.len() {
+        let half = u16::from_le_bytes([code[n], code[n + 1]]);
+        if half & 3 != 3 {
+            if half & 0xe003 == 0 && half & 0x1c == 0 && half != 0 {
+                return true;
+            }
+            n += 2;
+            continue;
+        }
+        let Some(insn) = code.get(n..n + 4) else {
+            break;
+        };
+        if u32::from_le_bytes(insn.try_into().unwrap()) & 0xfffff == 0x10413 {
+            return true;
+        }
+        n += 4;
+    }
+    false
+}
+
+// Whether `code`, the start of a function, sets up the frame pointer:
+// `addi.d $fp, $sp, n`.
+#[cfg(target_arch = "loongarch64")]
+fn sets_frame_pointer(code: &[u8], _thumb: bool) -> bool {
+    words(code).any(|insn| insn & 0xffc003ff == 0x02c00076)
+}
+
+// Whether `code`, the start of a function, sets up the frame pointer:
+// `add r11, sp, #n` or `mov r11, sp` in ARM state, `add r7, sp, #n` or
+// `mov r7, sp` in Thumb state.
+#[cfg(target_arch = "arm")]
+fn sets_frame_pointer(code: &[u8], thumb: bool) -> bool {
+    if thumb {
+        return code
+            .chunks_exact(2)
+            .map(|v| u16::from_le_bytes([v[0], v[1]]))
+            .any(|half| half & 0xff00 == 0xaf00 || half == 0x466f);
+    }
+    words(code).any(|insn| insn & 0x0ffff000 == 0x028db000 || insn & 0x0fffffff == 0x01a0b00d)
+}
+
+// The little-endian instruction words of `code`.
+#[cfg(any(target_arch = "aarch64", target_arch = "loongarch64", target_arch = "arm"))]
+fn words(code: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    code.chunks_exact(4)
+        .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_frame_pointers() {
+        let checks = verify_frame_pointers();
+        // This crate is built with frame pointers.
+        let exe = std::env::current_exe().unwrap();
+        let check = checks.iter().find(|c| c.path == exe).unwrap();
+        assert!(check.with_frame_pointer > 0, "{:?}", check);
+        assert!(check.with_frame_pointer <= check.functions);
+        assert!(checks.iter().any(|c| c.functions > 0));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_sets_frame_pointer() {
+        assert!(sets_frame_pointer(&[0x55, 0x48, 0x89, 0xe5, 0x41, 0x57], false));
+        assert!(sets_frame_pointer(
+            &[0xf3, 0x0f, 0x1e, 0xfa, 0x55, 0x48, 0x89, 0xe5],
+            false
+        ));
+        // push %rbx; sub $0x10, %rsp
+        assert!(!sets_frame_pointer(&[0x53, 0x48, 0x83, 0xec, 0x10], false));
+        assert!(!sets_frame_pointer(&[0x55], false));
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_sets_frame_pointer() {
+        // paciasp; stp x29, x30, [sp, #-16]!; mov x29, sp
+        let code = [0xd503233fu32, 0xa9bf7bfd, 0x910003fd];
+        let code: Vec<u8> = code.iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert!(sets_frame_pointer(&code, false));
+        // sub sp, sp, #16; ret
+        let code = [0xd10043ffu32, 0xd65f03c0];
+        let code: Vec<u8> = code.iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert!(!sets_frame_pointer(&code, false));
+    }
+
+    #[test]
+    fn test_module_check() {
+        let check = |functions, with_frame_pointer| ModuleCheck {
+            path: PathBuf::new(),
+            functions,
+            with_frame_pointer,
+        };
+        assert_eq!(check(0, 0).ratio(), None);
+        assert!(check(0, 0).maintains_frame_pointers());
+        assert_eq!(check(4, 3).ratio(), Some(0.75));
+        assert!(check(4, 3).maintains_frame_pointers());
+        assert!(!check(10, 1).maintains_frame_pointers());
+    }
+}
@@ -0,0 +1,93 @@
+//! Custom "X by call stack" profiles.
+//!
+//! [`record`] captures the current call-stack and adds a user-defined weight
+//! (bytes processed, retries, cache misses from a software counter, ...) to it.
+//! Weights are aggregated per `(kind, stack)` in a process-wide table which can
+//! be drained with [`take`].
+//!
+//! ```rust
+//! tracefp::events::record("bytes_written", 4096);
+//! tracefp::events::record("bytes_written", 512);
+//!
+//! let stacks = tracefp::events::take();
+//! let total: u64 = stacks.iter().filter(|s| s.kind == "bytes_written").map(|s| s.weight).sum();
+//! assert_eq!(total, 4608);
+//! ```
+//!
+//! Recording takes a lock and allocates, so it must not be used from a signal
+//! handler.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The aggregated weight of one `(kind, stack)` pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventStack {
+    /// The kind passed to [`record`].
+    pub kind: &'static str,
+    /// The PCs of the stack, innermost frame first, as passed to the closure of
+    /// [`trace`](crate::trace).
+    pub stack: Vec<u64>,
+    /// Sum of the weights recorded with this stack.
+    pub weight: u64,
+    /// Number of times an event was recorded with this stack.
+    pub count: u64,
+}
+
+type Table = HashMap<(&'static str, Vec<u64>), (u64, u64)>;
+
+static EVENTS: Mutex<Option<Table>> = Mutex::new(None);
+
+/// Capture the current call-stack and add `weight` to it under `kind`.
+pub fn record(kind: &'static str, weight: u64) {
+    let mut stack = Vec::with_capacity(32);
+    crate::trace(|pc| {
+        stack.push(pc);
+        true
+    });
+    let mut events = EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = events
+        .get_or_insert_with(HashMap::new)
+        .entry((kind, stack))
+        .or_default();
+    entry.0 = entry.0.saturating_add(weight);
+    entry.1 += 1;
+}
+
+/// Remove and return everything recorded so far, in no particular order.
+pub fn take() -> Vec<EventStack> {
+    let events = EVENTS.lock().unwrap_or_else(|e| e.into_inner()).take();
+    events
+        .unwrap_or_default()
+        .into_iter()
+        .map(|((kind, stack), (weight, count))| EventStack {
+            kind,
+            stack,
+            weight,
+            count,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn record_in_loop(kind: &'static str) {
+        for n in 1..=3 {
+            record(kind, n);
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        record_in_loop("test_record");
+        let stacks: Vec<_> = take().into_iter().filter(|s| s.kind == "test_record").collect();
+        assert_eq!(stacks.len(), 1);
+        assert_eq!(stacks[0].weight, 6);
+        assert_eq!(stacks[0].count, 3);
+        assert!(!stacks[0].stack.is_empty());
+        assert!(take().iter().all(|s| s.kind != "test_record"));
+    }
+}
@@ -0,0 +1,130 @@
+//! Early warning for unbounded recursion.
+
+use std::cell::Cell;
+
+/// Watches the depth of its own thread's call-stack and invokes a callback
+/// once it grows beyond a threshold.
+///
+/// Walking the stack on every call of a hot recursive function would be far
+/// too expensive, so the depth is only measured on every `interval`-th call
+/// of [`check`](RecursionGuard::check). The callback fires once when the
+/// threshold is first exceeded and is re-armed as soon as a measurement falls
+/// back below it.
+///
+/// A guard keeps its state in cells and is meant to be owned by one thread,
+/// typically through a `thread_local!`:
+///
+/// ```rust
+/// use tracefp::guard::RecursionGuard;
+///
+/// thread_local! {
+///     static GUARD: RecursionGuard<fn(usize, &[u64])> = RecursionGuard::new(10000, |depth, stack| {
+///         eprintln!("stack depth {} exceeds 10000, innermost pc: {:#x}", depth, stack[0]);
+///     });
+/// }
+///
+/// fn walk(n: usize) -> usize {
+///     GUARD.with(|g| g.check());
+///     if n == 0 { 0 } else { walk(n - 1) + 1 }
+/// }
+///
+/// assert_eq!(walk(100), 100);
+/// ```
+pub struct RecursionGuard<F> {
+    threshold: usize,
+    interval: u32,
+    calls: Cell<u32>,
+    fired: Cell<bool>,
+    callback: F,
+}
+
+impl<F> RecursionGuard<F>
+where
+    F: Fn(usize, &[u64]),
+{
+    /// Creates a guard that calls `callback` with the measured depth and the
+    /// offending stack once the depth exceeds `threshold` frames.
+    pub fn new(threshold: usize, callback: F) -> Self {
+        Self {
+            threshold,
+            interval: 64,
+            calls: Cell::new(0),
+            fired: Cell::new(false),
+            callback,
+        }
+    }
+
+    /// Sets how many calls of [`check`](RecursionGuard::check) pass between
+    /// two depth measurements. Defaults to 64, values below 1 are treated as 1.
+    pub fn interval(mut self, interval: u32) -> Self {
+        self.interval = interval.max(1);
+        self
+    }
+
+    /// Counts a call and measures the stack depth if the sampling interval
+    /// has elapsed. Returns `true` if the callback was invoked.
+    pub fn check(&self) -> bool {
+        let calls = self.calls.get() + 1;
+        if calls < self.interval {
+            self.calls.set(calls);
+            return false;
+        }
+        self.calls.set(0);
+        let depth = crate::metrics::depth();
+        if depth <= self.threshold {
+            self.fired.set(false);
+            return false;
+        }
+        if self.fired.replace(true) {
+            return false;
+        }
+        let mut stack = Vec::with_capacity(depth);
+        crate::trace(|pc| {
+            stack.push(pc);
+            true
+        });
+        (self.callback)(depth, &stack);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn recurse(guard: &RecursionGuard<impl Fn(usize, &[u64])>, n: usize) {
+        guard.check();
+        if n > 0 {
+            recurse(guard, n - 1);
+        }
+        std::hint::black_box(n);
+    }
+
+    #[test]
+    fn test_recursion_guard() {
+        let base = crate::metrics::depth();
+        let hits = Cell::new(0);
+        let guard = RecursionGuard::new(base + 50, |depth, stack| {
+            assert!(depth > base + 50);
+            assert!(!stack.is_empty());
+            hits.set(hits.get() + 1);
+        })
+        .interval(1);
+        recurse(&guard, 10);
+        assert_eq!(hits.get(), 0);
+        recurse(&guard, 100);
+        assert_eq!(hits.get(), 1);
+        recurse(&guard, 100);
+        assert_eq!(hits.get(), 2);
+    }
+
+    #[test]
+    fn test_recursion_guard_interval() {
+        let guard = RecursionGuard::new(0, |_, _| {}).interval(3);
+        assert!(!guard.check());
+        assert!(!guard.check());
+        assert!(guard.check());
+        assert!(!guard.check());
+    }
+}
@@ -0,0 +1,146 @@
+//! A registry of the code ranges of JIT compilers.
+//!
+//! Code generated at runtime (LuaJIT, wasmtime, the JIT of a database, ...)
+//! belongs to no module, so its PCs cannot be told apart from garbage. A
+//! runtime that generates code registers its ranges with
+//! [`register_jit_region`], after which the frames of that code carry the
+//! region in [`Frame::jit_region`](crate::Frame::jit_region).
+//!
+//! ```rust
+//! # let code = vec![0u8; 4096];
+//! # let start = code.as_ptr() as u64;
+//! use tracefp::jit::{jit_region_name, register_jit_region, unregister_jit_region};
+//!
+//! let region = register_jit_region(start, 4096, "lua:main.lua:12").unwrap();
+//! // ... run the generated code ...
+//! tracefp::trace_frames(|frame| {
+//!     if let Some(region) = frame.jit_region {
+//!         println!("{:#x} {}", frame.pc, jit_region_name(region).unwrap_or_default());
+//!     }
+//!     true
+//! });
+//! unregister_jit_region(region);
+//! ```
+//!
+//! The walk consults the registry for every frame, which is cheap while it is
+//! empty and grows with the number of regions up to [`MAX_JIT_REGIONS`].
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The number of regions that can be registered at the same time.
+pub const MAX_JIT_REGIONS: usize = 64;
+
+/// A registered region, see [`register_jit_region`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JitRegionId(usize);
+
+// The range `[start, end)` of a region, empty if `end` is 0.
+struct Slot {
+    start: AtomicU64,
+    end: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = Slot {
+    start: AtomicU64::new(0),
+    end: AtomicU64::new(0),
+};
+static SLOTS: [Slot; MAX_JIT_REGIONS] = [EMPTY; MAX_JIT_REGIONS];
+// The number of registered regions.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "std")]
+static NAMES: std::sync::Mutex<Vec<Option<String>>> = std::sync::Mutex::new(Vec::new());
+
+/// Registers the `len` bytes of code at `start`, named `name`. Must not be
+/// called from a signal handler.
+///
+/// Returns `None` if `len` is 0 or [`MAX_JIT_REGIONS`] regions are already
+/// registered. Regions should not overlap, a PC in several of them is
+/// attributed to any of them.
+#[cfg(feature = "std")]
+pub fn register_jit_region(start: u64, len: u64, name: &str) -> Option<JitRegionId> {
+    let end = start.checked_add(len).filter(|_| len > 0)?;
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    names.resize(MAX_JIT_REGIONS, None);
+    let n = names.iter().position(Option::is_none)?;
+    names[n] = Some(name.to_string());
+    SLOTS[n].start.store(start, Ordering::Relaxed);
+    SLOTS[n].end.store(end, Ordering::Release);
+    COUNT.fetch_add(1, Ordering::Release);
+    Some(JitRegionId(n))
+}
+
+/// Removes a region registered with [`register_jit_region`]. Its id may be
+/// reused by the next registration.
+///
+/// A walk running at the same time may still attribute frames to it.
+#[cfg(feature = "std")]
+pub fn unregister_jit_region(region: JitRegionId) {
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(name @ Some(_)) = names.get_mut(region.0) {
+        *name = None;
+        SLOTS[region.0].end.store(0, Ordering::Release);
+        COUNT.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// The name `region` was registered with, `None` if it is not registered
+/// (anymore). Must not be called from a signal handler.
+#[cfg(feature = "std")]
+pub fn jit_region_name(region: JitRegionId) -> Option<String> {
+    let names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    names.get(region.0)?.clone()
+}
+
+/// Finds the registered region containing `pc`.
+///
+/// This does not allocate or take locks, so it can be called from a signal
+/// handler.
+pub fn find_jit_region(pc: u64) -> Option<JitRegionId> {
+    if COUNT.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    SLOTS
+        .iter()
+        .position(|slot| {
+            let end = slot.end.load(Ordering::Acquire);
+            end != 0 && (slot.start.load(Ordering::Relaxed)..end).contains(&pc)
+        })
+        .map(JitRegionId)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jit_region() {
+        assert!(register_jit_region(0x1000, 0, "empty").is_none());
+        let a = register_jit_region(0x1000, 0x100, "a").unwrap();
+        let b = register_jit_region(0x2000, 0x100, "b").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(find_jit_region(0x1000), Some(a));
+        assert_eq!(find_jit_region(0x20ff), Some(b));
+        assert_eq!(find_jit_region(0x1100), None);
+        assert_eq!(jit_region_name(b).as_deref(), Some("b"));
+
+        unregister_jit_region(a);
+        unregister_jit_region(a);
+        assert_eq!(find_jit_region(0x1000), None);
+        assert_eq!(jit_region_name(a), None);
+        unregister_jit_region(b);
+    }
+
+    #[test]
+    fn test_frame_jit_region() {
+        let region = register_jit_region(0x3000, 0x100, "c").unwrap();
+        let registers = crate::Registers::from_raw(0x3010, 0);
+        let options = crate::TraceOptions::default();
+        let cursor =
+            crate::UnwindCursor::new_with_reader(&registers, crate::memory::LocalMemory::new(), &options).unwrap();
+        assert_eq!(cursor.frame().jit_region, Some(region));
+        unregister_jit_region(region);
+    }
+}
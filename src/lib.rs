@@ -21,6 +21,24 @@
 //!
 //! > NOTE: When you're using this library on **macOS + aarch64**, you don't need to do anything, as all libraries for this platform turn on frame-pointer by default.
 //!
+//! # Tail calls
+//!
+//! A function that ends with a tail call jumps to its callee instead of calling it,
+//! reusing (or tearing down) its own frame first. The callee therefore returns
+//! directly to the tail-calling function's caller, and the tail-calling function
+//! never appears in the frame-pointer chain:
+//!
+//! ```text
+//! main -> a -> b (tail-calls c) -> c
+//!
+//! reported stack: c, a, main
+//! ```
+//!
+//! This is inherent to frame-pointer unwinding (and to return-address based unwinding
+//! in general), so if a caller you expect never shows up in a trace, check whether it
+//! was compiled into a tail call. Optimized builds perform this transformation freely;
+//! `-fno-optimize-sibling-calls` disables it for C/C++ code.
+//!
 //! # Examples
 //!
 //! ## Stack backtrace
@@ -81,10 +99,23 @@
 //!
 //! ## Stack backtrace in signal handler
 //!
+//! The handler must only make async-signal-safe calls, so the symbols are loaded
+//! with `tracefp::symbols::SymbolTable` before it is installed, and it writes with
+//! `write(2)`. The names are not demangled.
+//!
 //! ```rust
+//! use std::sync::OnceLock;
+//!
 //! use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGPROF};
+//! use tracefp::symbols::SymbolTable;
+//!
+//! // Loading symbols allocates and reads files, so it is done before the
+//! // handler is installed.
+//! static SYMBOLS: OnceLock<SymbolTable> = OnceLock::new();
 //!
 //! fn main() {
+//!     SYMBOLS.set(SymbolTable::load()).unwrap();
+//!
 //!     // Register perf signal handler.
 //!     let h = SigHandler::SigAction(perf_signal_handler);
 //!     let a = SigAction::new(h, SaFlags::SA_SIGINFO, SigSet::empty());
@@ -98,74 +129,188 @@
 //!     }
 //!
 //!     // Block until the signal handler finishes executing.
-//!     loop {}
+//!     loop {
+//!         std::thread::park();
+//!     }
 //! }
 //!
 //! #[no_mangle]
 //! pub extern "C" fn perf_signal_handler(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+//!     // Only async-signal-safe calls in here: no allocation, no locks, so no
+//!     // `println!` either.
+//!     let symbols = SYMBOLS.get();
 //!     tracefp::trace_from_ucontext(ucontext, |pc| {
-//!         println!("{:#x}", pc);
-//!         backtrace::resolve(pc as _, |s| {
-//!             println!("    {:?}", s.name());
-//!         });
+//!         write(hex(pc, &mut [0; 18]));
+//!         if let Some(symbol) = symbols.and_then(|s| s.resolve_symbol(pc)) {
+//!             write(b"\n    ");
+//!             write(symbol.name.as_bytes());
+//!         }
+//!         write(b"\n");
 //!         true
 //!     });
-//!     std::process::exit(0);
+//!     unsafe { libc::_exit(0) };
+//! }
+//!
+//! fn write(bytes: &[u8]) {
+//!     unsafe { libc::write(libc::STDOUT_FILENO, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+//! }
+//!
+//! // Format `value` as `0x...` into `buffer`.
+//! fn hex(mut value: u64, buffer: &mut [u8; 18]) -> &[u8] {
+//!     let mut n = buffer.len();
+//!     loop {
+//!         n -= 1;
+//!         buffer[n] = b"0123456789abcdef"[(value & 0xf) as usize];
+//!         value >>= 4;
+//!         if value == 0 {
+//!             break;
+//!         }
+//!     }
+//!     buffer[n - 2..n].copy_from_slice(b"0x");
+//!     &buffer[n - 2..]
 //! }
 //! ```
 //!
 //! Sample output:
 //!
 //! ```text
-//! 0x1c32e4824
-//!     Some("_thread_get_state")
-//! 0x100409093
-//!     Some(core::ops::function::FnOnce::call_once::h775fb44fbbe53d95)
-//! 0x1004090eb
-//!     Some(std::sys_common::backtrace::__rust_begin_short_backtrace::h3acd0b11747c5033)
-//! 0x100408a2b
-//!     Some(std::rt::lang_start::{{closure}}::hf7b77a4d60d2f840)
-//! 0x1004d7faf
-//!     Some(core::ops::function::impls::<impl core::ops::function::FnOnce<A> for &F>::call_once::h8eb3ac20f80eabfa)
-//!     Some(std::panicking::try::do_call::ha6ddf2c638427188)
-//!     Some(std::panicking::try::hda8741de507c1ad0)
-//!     Some(std::panic::catch_unwind::h82424a01f258bd39)
-//!     Some(std::rt::lang_start_internal::{{closure}}::h67e296ed5b030b7b)
-//!     Some(std::panicking::try::do_call::hd3dd7e7e10f6424e)
-//!     Some(std::panicking::try::ha0a7bd8122e3fb7c)
-//!     Some(std::panic::catch_unwind::h809b0e1092e9475d)
-//!     Some(std::rt::lang_start_internal::h358b6d58e23c88c7)
-//! 0x1004089f3
-//!     Some(std::rt::lang_start::hd321e36029dcfdd2)
-//! 0x1004093d7
-//!     Some("_main")
-//! 0x1007bd087
-//! 0x921a7fffffffffff
+//! 0x7f603b0ee267
+//!     kill
+//! 0x564b639ec3cd
+//!     _ZN4core3ops8function6FnOnce9call_once17h818ee51842a71040E
+//! 0x564b639ed290
+//!     _ZN3std3sys9backtrace28__rust_begin_short_backtrace17hfb3714cd106b6329E
+//! 0x564b639ebed3
+//!     _ZN3std2rt10lang_start28_$u7b$$u7b$closure$u7d$$u7d$17hca96a3617cb4391aE
+//! 0x564b63a2b2e3
+//!     _RNvNtCsjrHSEGnQ3l9_3std2rt19lang_start_internal
+//! 0x564b639ebeb7
+//!     _ZN3std2rt10lang_start17h09c50f8cda15f9f9E
+//! 0x564b639ec9c0
+//!     main
+//! 0x7f603b0d9249
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+mod cursor;
+mod options;
+#[cfg(feature = "std")]
+mod signal;
+mod sigtramp;
+mod stack;
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod thread;
+
+#[cfg(feature = "std")]
+pub mod aggregate;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "std")]
+pub mod compat;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod coredump;
+#[cfg(feature = "crosscheck")]
+pub mod crosscheck;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod events;
+#[cfg(feature = "std")]
+pub mod guard;
+pub mod jit;
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod merge;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod modules;
+#[cfg(feature = "std")]
+pub mod names;
+#[cfg(feature = "std")]
+pub mod profiler;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod remote;
+#[cfg(feature = "std")]
+pub mod report;
+#[cfg(feature = "std")]
+pub mod sample;
+#[cfg(feature = "std")]
+pub mod symbols;
+
+pub use cursor::{fp_rejections, reset_fp_rejections, FpRejections, StopReason, TraceOutcome, UnwindCursor};
+pub use memory::MemoryReader;
+pub use options::TraceOptions;
+pub use stack::StackBounds;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub use thread::{
+    clear_pool_thread, set_pool_thread, trace_all_threads, trace_thread, trace_thread_signal,
+    trace_thread_with_options, PoolThread, ThreadTrace, MAX_THREAD_FRAMES,
+};
+
+/// A single frame of a call-stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Frame {
+    /// The program counter of this frame.
+    ///
+    /// For every frame but the topmost one this is the return address minus 1,
+    /// which points into the call instruction rather than past it and therefore
+    /// resolves to the right function and line even for calls at the very end
+    /// of a function (e.g. calls to `noreturn` functions).
+    pub pc: u64,
+    /// The frame pointer of this frame.
+    pub fp: u64,
+    /// The stack pointer of this frame.
+    ///
+    /// For the topmost frame this is the actual register value. For the other
+    /// frames it is the stack pointer right after the callee returns, i.e. the
+    /// address just past the callee's frame record.
+    pub sp: u64,
+    /// Whether this is the topmost frame, whose registers come directly from
+    /// the captured context (the interrupted frame when unwinding from a
+    /// signal handler's `ucontext`).
+    pub is_top: bool,
+    /// Whether `pc` was derived from a return address by subtracting 1, see
+    /// [`pc`](Frame::pc). The topmost frame is never adjusted, the others are
+    /// unless [`TraceOptions::adjust_pc`] turned it off, or this is a signal
+    /// trampoline.
+    pub adjusted: bool,
+    /// Whether `pc` is the signal trampoline a signal handler returns into,
+    /// see [`TraceOptions::signal_trampolines`].
+    ///
+    /// The frames above it belong to the handler, the frames below it to the
+    /// interrupted code. The interrupted frame itself is only known from the
+    /// handler's `ucontext` and is missing from the chain, except on x86_64
+    /// and i686, where the walk recovers it from the signal frame and reports
+    /// it right below the trampoline, which also works through nested
+    /// signals.
+    pub is_signal_trampoline: bool,
+    /// The JIT region `pc` is in, see [`jit`].
+    pub jit_region: Option<jit::JitRegionId>,
+    /// Whether `pc` is in Thumb code. Return addresses into Thumb code have
+    /// the lowest bit set, which is stripped from `pc` so that it can be
+    /// symbolized. Always `false` except on arm.
+    pub thumb: bool,
+}
+
 /// Inspects the current call-stack, passing all active PCs into the closure
 /// provided to calculate a stack trace.
 ///
 /// The closure's return value is an indication of whether the backtrace should
 /// continue. A return value of `false` will terminate the backtrace and return
-/// immediately.
-pub fn trace<F>(f: F)
+/// immediately. The result tells whether the stack is complete, see
+/// [`TraceOutcome`].
+#[cfg(feature = "std")]
+pub fn trace<F>(f: F) -> TraceOutcome
 where
     F: FnMut(u64) -> bool,
 {
-    let mut ucontext: libc::ucontext_t = unsafe { std::mem::zeroed() };
-    #[cfg(target_os = "macos")]
-    {
-        let mut mcontext: libc::__darwin_mcontext64 = unsafe { std::mem::zeroed() };
-        ucontext.uc_mcontext = &mut mcontext as *mut libc::__darwin_mcontext64;
-    }
-    let ucontext = &mut ucontext as *mut libc::ucontext_t as *mut libc::c_void;
-    unsafe {
-        if getcontext(ucontext) != 0 {
-            return;
-        }
-    }
-    trace_from_ucontext(ucontext, f)
+    trace_with_options(&TraceOptions::default(), f)
 }
 
 /// Inspects the call-stack from `ucontext`, passing all active PCs into the closure
@@ -173,52 +318,544 @@ where
 ///
 /// The closure's return value is an indication of whether the backtrace should
 /// continue. A return value of `false` will terminate the backtrace and return
-/// immediately.
-pub fn trace_from_ucontext<F>(ucontext: *mut libc::c_void, mut f: F)
+/// immediately. The result tells whether the stack is complete, see
+/// [`TraceOutcome`].
+#[cfg(feature = "std")]
+pub fn trace_from_ucontext<F>(ucontext: *mut libc::c_void, f: F) -> TraceOutcome
+where
+    F: FnMut(u64) -> bool,
+{
+    trace_from_ucontext_with_options(ucontext, &TraceOptions::default(), f)
+}
+
+/// Inspects the current call-stack, passing all active frames into the closure
+/// provided to calculate a stack trace.
+///
+/// This is the same as [`trace`], but the closure receives a full [`Frame`]
+/// instead of only its PC.
+#[cfg(feature = "std")]
+pub fn trace_frames<F>(f: F) -> TraceOutcome
+where
+    F: FnMut(&Frame) -> bool,
+{
+    trace_frames_with_options(&TraceOptions::default(), f)
+}
+
+/// Inspects the call-stack from `ucontext`, passing all active frames into the
+/// closure provided to calculate a stack trace.
+///
+/// This is the same as [`trace_from_ucontext`], but the closure receives a full
+/// [`Frame`] instead of only its PC.
+#[cfg(feature = "std")]
+pub fn trace_frames_from_ucontext<F>(ucontext: *mut libc::c_void, f: F) -> TraceOutcome
+where
+    F: FnMut(&Frame) -> bool,
+{
+    trace_frames_from_ucontext_with_options(ucontext, &TraceOptions::default(), f)
+}
+
+/// Same as [`trace`], but unwinds according to `options`.
+#[cfg(feature = "std")]
+pub fn trace_with_options<F>(options: &TraceOptions, mut f: F) -> TraceOutcome
 where
     F: FnMut(u64) -> bool,
 {
-    let Registers { mut pc, mut fp } = match Registers::from_ucontext(ucontext) {
-        Some(v) => v,
-        None => return,
+    trace_frames_with_options(options, |frame| f(frame.pc))
+}
+
+/// Same as [`trace_from_ucontext`], but unwinds according to `options`.
+#[cfg(feature = "std")]
+pub fn trace_from_ucontext_with_options<F>(
+    ucontext: *mut libc::c_void,
+    options: &TraceOptions,
+    mut f: F,
+) -> TraceOutcome
+where
+    F: FnMut(u64) -> bool,
+{
+    trace_frames_from_ucontext_with_options(ucontext, options, |frame| f(frame.pc))
+}
+
+/// Same as [`trace_frames`], but unwinds according to `options`.
+#[cfg(feature = "std")]
+pub fn trace_frames_with_options<F>(options: &TraceOptions, f: F) -> TraceOutcome
+where
+    F: FnMut(&Frame) -> bool,
+{
+    if options.stack_bounds == options::StackBoundsMode::Auto {
+        StackBounds::current();
+    }
+    match UnwindCursor::new_local(&Registers::current(), options) {
+        Some(cursor) => walk(cursor, options, f),
+        None => TraceOutcome::InvalidFramePointer,
+    }
+}
+
+/// Same as [`trace_frames_from_ucontext`], but unwinds according to `options`.
+#[cfg(feature = "std")]
+pub fn trace_frames_from_ucontext_with_options<F>(
+    ucontext: *mut libc::c_void,
+    options: &TraceOptions,
+    f: F,
+) -> TraceOutcome
+where
+    F: FnMut(&Frame) -> bool,
+{
+    let Some(registers) = Registers::from_ucontext(ucontext) else {
+        return TraceOutcome::NullUcontext;
     };
-    if !f(pc) {
-        return;
+    match UnwindCursor::new_local(&registers, options) {
+        Some(cursor) => walk(cursor, options, f),
+        None => TraceOutcome::InvalidFramePointer,
     }
-    while fp != 0 {
-        pc = match load::<u64>(fp + 8) {
-            Some(v) => v,
-            None => return,
-        };
-        pc -= 1;
-        if !f(pc) {
-            return;
+}
+
+// Pass the frames of `cursor` into `f`, applying `skip` and `max_depth`.
+fn walk<R, F>(mut cursor: UnwindCursor<R>, options: &TraceOptions, mut f: F) -> TraceOutcome
+where
+    R: MemoryReader,
+    F: FnMut(&Frame) -> bool,
+{
+    let mut skip = options.skip;
+    let mut depth = 0;
+    loop {
+        if skip > 0 {
+            skip -= 1;
+        } else if depth < options.max_depth {
+            if !f(cursor.frame()) {
+                return TraceOutcome::CallbackStopped;
+            }
+            depth += 1;
+        }
+        // One more step tells whether the limit cut the stack short.
+        if !cursor.step() {
+            return cursor.stop_reason().map_or(TraceOutcome::Completed, TraceOutcome::from);
+        }
+        if depth >= options.max_depth {
+            return TraceOutcome::MaxDepthReached;
         }
-        fp = match load::<u64>(fp) {
-            Some(v) => v,
-            None => return,
-        };
     }
 }
 
-extern "C" {
-    // getcontext() in libc.
-    //
-    // We declare here instead of using `libc::getcontext()` directly because
-    // `libc::getcontext()` is not found on macOS.
-    fn getcontext(_ucontext: *mut libc::c_void) -> libc::c_int;
+/// Inspects the call-stack starting at `registers` in the current process, and
+/// passes all active PCs into the closure provided.
+///
+/// This unwinds a stack that is not running, e.g. the stack of a parked fiber
+/// or green thread whose registers were saved by its context switch, see
+/// [`Registers::from_raw`].
+#[cfg(feature = "std")]
+pub fn trace_from_registers<F>(registers: &Registers, f: F) -> TraceOutcome
+where
+    F: FnMut(u64) -> bool,
+{
+    trace_from_registers_with_options(registers, &TraceOptions::default(), f)
+}
+
+/// Same as [`trace_from_registers`], but unwinds according to `options`.
+#[cfg(feature = "std")]
+pub fn trace_from_registers_with_options<F>(registers: &Registers, options: &TraceOptions, mut f: F) -> TraceOutcome
+where
+    F: FnMut(u64) -> bool,
+{
+    match UnwindCursor::new_local(registers, options) {
+        Some(cursor) => walk(cursor, options, |frame| f(frame.pc)),
+        None => TraceOutcome::InvalidFramePointer,
+    }
+}
+
+/// Inspects the call-stack starting at `registers`, reading its memory through
+/// `reader`, and passes all active PCs into the closure provided.
+///
+/// This decouples the unwinding from the memory of the current process, e.g.
+/// to unwind another process or a core dump. See
+/// [`UnwindCursor::new_with_reader`] for more control.
+pub fn trace_with_reader<R, F>(registers: &Registers, reader: R, mut f: F) -> TraceOutcome
+where
+    R: MemoryReader,
+    F: FnMut(u64) -> bool,
+{
+    let options = TraceOptions::default();
+    match UnwindCursor::new_with_reader(registers, reader, &options) {
+        Some(cursor) => walk(cursor, &options, |frame| f(frame.pc)),
+        None => TraceOutcome::InvalidFramePointer,
+    }
+}
+
+// The lowest address a frame pointer can take.
+//
+// The first page is never mapped, so anything below it is a terminating 0
+// or garbage left in the register, not a frame.
+const MIN_FP: u64 = 4096;
+
+// The (exclusive) end of the user-space half of the address space.
+//
+// Addresses at or above it are either in the kernel half or non-canonical,
+// neither of which can hold a user-space frame.
+#[cfg(target_arch = "x86_64")]
+const USER_SPACE_END: u64 = 1 << 47;
+#[cfg(target_arch = "aarch64")]
+const USER_SPACE_END: u64 = 1 << 52;
+#[cfg(any(target_arch = "riscv64", target_arch = "loongarch64"))]
+const USER_SPACE_END: u64 = 1 << 56;
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+const USER_SPACE_END: u64 = 1 << 32;
+
+// The size of a stack slot, and of each of the two entries of a frame record.
+const WORD: u64 = core::mem::size_of::<usize>() as u64;
+
+// The distance from a frame pointer down to its frame record, i.e. the pair
+// of the caller's frame pointer followed by the return address, which is the
+// same on all supported architectures.
+//
+// On x86, arm and their 64-bit relatives the frame pointer points at the
+// record itself. On riscv64 and loongarch64 it points at the canonical frame
+// address (the stack pointer before the call), right above the record.
+//
+// For arm this is the frame record layout of the AAPCS as emitted by LLVM
+// (`push {r11, lr}; mov r11, sp`). Code generated by GCC in ARM state points
+// the frame pointer at the saved lr instead and cannot be unwound.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm"
+))]
+const RECORD_OFFSET: u64 = 0;
+#[cfg(any(target_arch = "riscv64", target_arch = "loongarch64"))]
+const RECORD_OFFSET: u64 = 16;
+
+// The alignment of frame records.
+//
+// On x86_64 only the 8 byte alignment of pushed registers is guaranteed for
+// hand-written assembly, on the other 64-bit architectures both GCC and LLVM
+// keep frame records 16 byte aligned, like the stack pointer. On 32-bit
+// architectures only word alignment is guaranteed.
+#[cfg(target_arch = "x86_64")]
+const FP_ALIGN: u64 = 8;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64", target_arch = "loongarch64"))]
+const FP_ALIGN: u64 = 16;
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+const FP_ALIGN: u64 = 4;
+
+// Turn a raw pointer value read from a register or the stack into the
+// address it actually refers to, or `None` if no such address exists.
+//
+// On x86_64 the upper 17 bits of a valid address are copies of bit 47, any
+// other value faults when dereferenced.
+#[inline]
+#[cfg(target_arch = "x86_64")]
+fn canonicalize(address: u64) -> Option<u64> {
+    if ((address << 16) as i64 >> 16) as u64 == address {
+        Some(address)
+    } else {
+        None
+    }
+}
+
+// Turn a raw pointer value read from a register or the stack into the
+// address it actually refers to, or `None` if no such address exists.
+//
+// On aarch64 the top byte is ignored by address translation (TBI) and may
+// carry a tag (HWASan, MTE, ...), so it has to be stripped before the value
+// is compared, dereferenced or reported. Bit 55 selects the lower or upper
+// half of the address space and is copied into it.
+#[inline]
+#[cfg(target_arch = "aarch64")]
+fn canonicalize(address: u64) -> Option<u64> {
+    Some(((address << 8) as i64 >> 8) as u64)
+}
+
+// Turn a raw pointer value read from a register or the stack into the
+// address it actually refers to, or `None` if no such address exists.
+//
+// Neither riscv64 nor loongarch64 ignore any address bits, and the number of
+// valid bits depends on the paging mode, which is left to `check_fp`.
+#[inline]
+#[cfg(any(target_arch = "riscv64", target_arch = "loongarch64"))]
+fn canonicalize(address: u64) -> Option<u64> {
+    Some(address)
+}
+
+// Turn a raw pointer value read from a register or the stack into the
+// address it actually refers to, or `None` if no such address exists.
+//
+// On 32-bit architectures any value that fits into a word is an address.
+#[inline]
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+fn canonicalize(address: u64) -> Option<u64> {
+    if address <= u32::MAX as u64 {
+        Some(address)
+    } else {
+        None
+    }
+}
+
+// Strip the pointer authentication code from a signed return address.
+//
+// With pointer authentication (arm64e on macOS, `-mbranch-protection` on
+// Linux) the return addresses saved in frame records carry a signature in
+// their unused upper bits. XPACLRI removes it from the value in LR, and is
+// encoded in the hint space, so CPUs without pointer authentication execute
+// it as a NOP and leave the (then unsigned) address untouched.
+#[inline]
+#[cfg(target_arch = "aarch64")]
+fn strip_pac(address: u64) -> u64 {
+    let mut address = address;
+    unsafe {
+        core::arch::asm!("hint #7", inout("lr") address, options(nomem, nostack, preserves_flags));
+    }
+    address
+}
+
+// Strip the pointer authentication code from a signed return address.
+//
+// Only aarch64 signs return addresses.
+#[inline]
+#[cfg(not(target_arch = "aarch64"))]
+fn strip_pac(address: u64) -> u64 {
+    address
+}
+
+// Split a code address into the address of the instruction and whether it is
+// Thumb code, which is marked by the lowest bit on arm.
+#[inline]
+fn split_thumb(address: u64) -> (u64, bool) {
+    match cfg!(target_arch = "arm") {
+        true => (address & !1, address & 1 != 0),
+        false => (address, false),
+    }
+}
+
+// Whether an instruction can start at `pc`, see
+// `TraceOptions::check_pc_alignment`.
+#[inline]
+fn is_aligned_pc(pc: u64, thumb: bool) -> bool {
+    let align = if cfg!(any(target_arch = "x86_64", target_arch = "x86")) {
+        1
+    } else if cfg!(target_arch = "riscv64") || thumb {
+        2
+    } else {
+        4
+    };
+    pc.is_multiple_of(align)
+}
+
+// Check whether `fp` can possibly point to a frame record. Values that fail
+// this check terminate the chain without being dereferenced.
+#[inline]
+fn check_fp(fp: u64) -> Result<(), cursor::FpRejection> {
+    if fp < MIN_FP {
+        Err(cursor::FpRejection::Low)
+    } else if fp >= USER_SPACE_END {
+        Err(cursor::FpRejection::Kernel)
+    } else {
+        Ok(())
+    }
+}
+
+/// The registers an unwind starts from.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Registers {
+    /// The program counter. On arm the lowest bit is set while executing
+    /// Thumb code, like in return addresses.
+    pub pc: u64,
+    /// The frame pointer.
+    pub fp: u64,
+    /// The stack pointer.
+    pub sp: u64,
+    /// The link register, which holds the return address of a leaf function
+    /// that has not saved it in a frame record (yet), see
+    /// [`TraceOptions::link_register`]. 0 where it is not used for unwinding.
+    pub lr: u64,
 }
 
-// Register context for stack backtracking.
-#[derive(Debug, Copy, Clone)]
-struct Registers {
-    pc: u64,
-    fp: u64,
+impl Registers {
+    /// Creates the registers from the program counter, frame pointer, stack
+    /// pointer and link register. Pass 0 for the link register where it is
+    /// not used for unwinding.
+    pub fn new(pc: u64, fp: u64, sp: u64, lr: u64) -> Self {
+        Self { pc, fp, sp, lr }
+    }
+
+    /// Creates the registers of a stack from the program counter and frame
+    /// pointer alone, e.g. as saved by the context switch of a fiber.
+    ///
+    /// The stack pointer is taken to be the frame record `fp` points to, and
+    /// the link register is not used.
+    pub fn from_raw(pc: u64, fp: u64) -> Self {
+        Self {
+            pc,
+            fp,
+            sp: fp.saturating_sub(RECORD_OFFSET),
+            lr: 0,
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 impl Registers {
+    // The registers of the calling function, which must have a frame record
+    // of its own, i.e. not be a leaf. The PC is the address of the capture.
+    #[inline(always)]
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn current() -> Self {
+        let (pc, fp, sp): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "lea {pc}, [rip]",
+                "mov {fp}, rbp",
+                "mov {sp}, rsp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self { pc, fp, sp, lr: 0 }
+    }
+
+    // The registers of the calling function, which must have a frame record
+    // of its own, i.e. not be a leaf. The PC is the address of the capture.
+    //
+    // The link register is not reported, the return address of a non-leaf
+    // function is in its frame record.
+    #[inline(always)]
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn current() -> Self {
+        let (pc, fp, sp): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "adr {pc}, .",
+                "mov {fp}, x29",
+                "mov {sp}, sp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self { pc, fp, sp, lr: 0 }
+    }
+
+    // The registers of the calling function, which must have a frame record
+    // of its own, i.e. not be a leaf. The PC is the address of the capture.
+    #[inline(always)]
+    #[cfg(target_arch = "riscv64")]
+    pub(crate) fn current() -> Self {
+        let (pc, fp, sp): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "auipc {pc}, 0",
+                "mv {fp}, s0",
+                "mv {sp}, sp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self { pc, fp, sp, lr: 0 }
+    }
+
+    // The registers of the calling function, which must have a frame record
+    // of its own, i.e. not be a leaf. The PC is the address of the capture.
+    #[inline(always)]
+    #[cfg(target_arch = "loongarch64")]
+    pub(crate) fn current() -> Self {
+        let (pc, fp, sp): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "pcaddi {pc}, 0",
+                "move {fp}, $fp",
+                "move {sp}, $sp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self { pc, fp, sp, lr: 0 }
+    }
+
+    // The registers of the calling function, which must have a frame record
+    // of its own, i.e. not be a leaf. The PC is the address of the capture.
+    //
+    // There is no PC-relative addressing, the PC is the return address of a
+    // call to the next instruction.
+    #[inline(always)]
+    #[cfg(target_arch = "x86")]
+    pub(crate) fn current() -> Self {
+        let (pc, fp, sp): (u32, u32, u32);
+        unsafe {
+            core::arch::asm!(
+                "call 2f",
+                "2:",
+                "pop {pc}",
+                "mov {fp}, ebp",
+                "mov {sp}, esp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, preserves_flags),
+            );
+        }
+        Self {
+            pc: pc as u64,
+            fp: fp as u64,
+            sp: sp as u64,
+            lr: 0,
+        }
+    }
+
+    // The registers of the calling function, which must have a frame record
+    // of its own, i.e. not be a leaf. The PC is the address of the capture.
+    //
+    // The frame pointer is r11 in ARM state and r7 in Thumb state.
+    #[inline(always)]
+    #[cfg(target_arch = "arm")]
+    pub(crate) fn current() -> Self {
+        let (pc, fp, sp): (u32, u32, u32);
+        unsafe {
+            #[cfg(not(target_feature = "thumb-mode"))]
+            core::arch::asm!(
+                "adr {pc}, .",
+                "mov {fp}, r11",
+                "mov {sp}, sp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, nostack, preserves_flags),
+            );
+            #[cfg(target_feature = "thumb-mode")]
+            core::arch::asm!(
+                "adr {pc}, .",
+                "mov {fp}, r7",
+                "mov {sp}, sp",
+                pc = out(reg) pc,
+                fp = out(reg) fp,
+                sp = out(reg) sp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Self {
+            pc: pc as u64 | cfg!(target_feature = "thumb-mode") as u64,
+            fp: fp as u64,
+            sp: sp as u64,
+            lr: 0,
+        }
+    }
+
+    /// Reads the registers saved in `ucontext`, a pointer to a `ucontext_t` as
+    /// passed to a signal handler.
+    ///
+    /// Returns `None` if `ucontext` is null.
+    pub fn from_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+        Self::read_ucontext(ucontext)
+    }
+
     #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
-    fn from_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
         let ucontext = ucontext as *mut libc::ucontext_t;
         if ucontext.is_null() {
             return None;
@@ -227,11 +864,13 @@ impl Registers {
         Some(Self {
             pc: mcontext.gregs[libc::REG_RIP as usize] as u64,
             fp: mcontext.gregs[libc::REG_RBP as usize] as u64,
+            sp: mcontext.gregs[libc::REG_RSP as usize] as u64,
+            lr: 0,
         })
     }
 
     #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
-    fn from_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
         let ucontext = ucontext as *mut libc::ucontext_t;
         if ucontext.is_null() {
             return None;
@@ -243,13 +882,15 @@ impl Registers {
             }
             Some(Self {
                 pc: (*mcontext).__ss.__rip,
-                fp: (*mcontext).__ss.__rbx,
+                fp: (*mcontext).__ss.__rbp,
+                sp: (*mcontext).__ss.__rsp,
+                lr: 0,
             })
         }
     }
 
     #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
-    fn from_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
         let ucontext = ucontext as *mut libc::ucontext_t;
         if ucontext.is_null() {
             return None;
@@ -258,11 +899,81 @@ impl Registers {
         Some(Self {
             pc: mcontext.pc,
             fp: mcontext.regs[29],
+            sp: mcontext.sp,
+            lr: mcontext.regs[30],
+        })
+    }
+
+    #[cfg(all(target_arch = "x86", target_os = "linux"))]
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        if ucontext.is_null() {
+            return None;
+        }
+        let mcontext = unsafe { &(*ucontext).uc_mcontext };
+        Some(Self {
+            pc: mcontext.gregs[libc::REG_EIP as usize] as u32 as u64,
+            fp: mcontext.gregs[libc::REG_EBP as usize] as u32 as u64,
+            sp: mcontext.gregs[libc::REG_ESP as usize] as u32 as u64,
+            lr: 0,
+        })
+    }
+
+    #[cfg(all(target_arch = "arm", target_os = "linux"))]
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+        // The T bit of the CPSR, set while executing Thumb code.
+        const CPSR_T: libc::c_ulong = 1 << 5;
+
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        if ucontext.is_null() {
+            return None;
+        }
+        // The frame pointer is r11 in ARM state and r7 in Thumb state.
+        let mcontext = unsafe { &(*ucontext).uc_mcontext };
+        let thumb = mcontext.arm_cpsr & CPSR_T != 0;
+        let fp = if thumb { mcontext.arm_r7 } else { mcontext.arm_fp };
+        Some(Self {
+            pc: mcontext.arm_pc as u64 | thumb as u64,
+            fp: fp as u64,
+            sp: mcontext.arm_sp as u64,
+            lr: 0,
+        })
+    }
+
+    #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        if ucontext.is_null() {
+            return None;
+        }
+        // __gregs[0] holds the pc, the others x1..x31: sp is x2, fp is s0/x8.
+        let mcontext = unsafe { &(*ucontext).uc_mcontext };
+        Some(Self {
+            pc: mcontext.__gregs[0],
+            fp: mcontext.__gregs[8],
+            sp: mcontext.__gregs[2],
+            lr: 0,
+        })
+    }
+
+    #[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        if ucontext.is_null() {
+            return None;
+        }
+        // sp is $r3, fp is $r22.
+        let mcontext = unsafe { &(*ucontext).uc_mcontext };
+        Some(Self {
+            pc: mcontext.__pc,
+            fp: mcontext.__gregs[22],
+            sp: mcontext.__gregs[3],
+            lr: 0,
         })
     }
 
     #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
-    fn from_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
+    fn read_ucontext(ucontext: *mut libc::c_void) -> Option<Self> {
         let ucontext = ucontext as *mut libc::ucontext_t;
         if ucontext.is_null() {
             return None;
@@ -275,6 +986,8 @@ impl Registers {
             Some(Self {
                 pc: (*mcontext).__ss.__pc,
                 fp: (*mcontext).__ss.__fp,
+                sp: (*mcontext).__ss.__sp,
+                lr: (*mcontext).__ss.__lr,
             })
         }
     }
@@ -288,7 +1001,7 @@ impl Registers {
 #[inline]
 #[cfg(not(feature = "memory-access-check"))]
 fn load<T: Copy>(address: u64) -> Option<T> {
-    unsafe { Some(*(address as *const T)) }
+    unsafe { Some((address as *const T).read_unaligned()) }
 }
 
 // Load the value at the `address`.
@@ -298,13 +1011,69 @@ fn load<T: Copy>(address: u64) -> Option<T> {
 #[inline]
 #[cfg(feature = "memory-access-check")]
 fn load<T: Copy>(address: u64) -> Option<T> {
-    if access_check::can_access(address) {
-        unsafe { Some(*(address as *const T)) }
+    if memory::can_access(address) {
+        unsafe { Some((address as *const T).read_unaligned()) }
     } else {
         None
     }
 }
 
+// Load the value at the `address` without any check.
+//
+// The caller must know that `address` is readable, e.g. because it lies
+// within the bounds of the stack being unwound.
+#[inline]
+unsafe fn load_unchecked<T: Copy>(address: u64) -> T {
+    (address as *const T).read_unaligned()
+}
+
+// The granularity at which `PageCache` remembers readable memory. This is
+// the smallest page size on every supported platform, so a granule is always
+// mapped or unmapped as a whole.
+const GRANULE: u64 = 4096;
+
+// The most recently used granules that were found readable during one
+// unwind.
+//
+// Frame records of consecutive frames are usually on the same few pages,
+// remembering them lets a deep walk perform a handful of memory access
+// checks instead of one per load.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct PageCache {
+    granules: [u64; 8],
+    next: usize,
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PageCache {
+    fn new() -> Self {
+        Self {
+            // Not a valid granule number.
+            granules: [u64::MAX; 8],
+            next: 0,
+        }
+    }
+
+    // Load the value at the `address` like `load`, skipping the memory access
+    // check if its granule was already found readable.
+    #[inline]
+    fn load<T: Copy>(&mut self, address: u64) -> Option<T> {
+        let granule = address / GRANULE;
+        if self.granules.contains(&granule) {
+            return unsafe { Some(load_unchecked(address)) };
+        }
+        let value = load(address)?;
+        self.granules[self.next] = granule;
+        self.next = (self.next + 1) % self.granules.len();
+        Some(value)
+    }
+}
+
 #[cfg(feature = "memory-access-check")]
 mod access_check {
     use std::mem::MaybeUninit;
@@ -396,13 +1165,13 @@ mod access_check {
 
     #[inline]
     #[cfg(target_os = "linux")]
-    fn errno() -> libc::c_int {
+    pub fn errno() -> libc::c_int {
         unsafe { (*libc::__errno_location()) as libc::c_int }
     }
 
     #[inline]
     #[cfg(target_os = "macos")]
-    fn errno() -> libc::c_int {
+    pub fn errno() -> libc::c_int {
         unsafe { (*libc::__error()) as libc::c_int }
     }
 
@@ -435,4 +1204,269 @@ mod tests {
         let loc = &val as *const u64 as u64;
         assert_eq!(load::<u64>(loc), Some(val));
     }
+
+    #[test]
+    fn test_page_cache() {
+        let mut cache = PageCache::new();
+        let vals = [1u64, 2, 3];
+        for val in &vals {
+            assert_eq!(cache.load::<u64>(val as *const u64 as u64), Some(*val));
+        }
+        let granule = &vals[0] as *const u64 as u64 / GRANULE;
+        assert!(cache.granules.contains(&granule));
+
+        #[cfg(feature = "memory-access-check")]
+        {
+            let mut cache = PageCache::new();
+            assert_eq!(cache.load::<u64>(8), None);
+            assert_eq!(cache.load::<u64>(8), None);
+            assert_eq!(cache, PageCache::new());
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_canonicalize() {
+        assert_eq!(canonicalize(0), Some(0));
+        assert_eq!(canonicalize(0x0000_7fff_ffff_ffff), Some(0x0000_7fff_ffff_ffff));
+        assert_eq!(canonicalize(0xffff_8000_0000_0000), Some(0xffff_8000_0000_0000));
+        assert_eq!(canonicalize(0x0000_8000_0000_0000), None);
+        assert_eq!(canonicalize(0xd82e_7fff_ffff_ffff), None);
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_canonicalize() {
+        assert_eq!(canonicalize(0), Some(0));
+        assert_eq!(canonicalize(0x0000_ffff_ffff_ffff), Some(0x0000_ffff_ffff_ffff));
+        assert_eq!(canonicalize(0xb400_0071_2345_6780), Some(0x0000_0071_2345_6780));
+        assert_eq!(canonicalize(0x0080_0000_1234_5678), Some(0xff80_0000_1234_5678));
+    }
+
+    #[test]
+    fn test_strip_pac() {
+        // Unsigned addresses are left alone, with or without PAC support.
+        let pc = test_strip_pac as fn() as usize as u64;
+        assert_eq!(strip_pac(pc), pc);
+        assert_eq!(strip_pac(0), 0);
+    }
+
+    #[test]
+    fn test_trace_frames() {
+        let mut frames = vec![];
+        trace_frames(|frame| {
+            frames.push(*frame);
+            true
+        });
+        assert!(frames.len() > 1);
+        assert!(frames[0].is_top);
+        assert!(!frames[0].adjusted);
+        for frame in &frames[1..] {
+            assert!(!frame.is_top);
+            assert!(frame.adjusted);
+        }
+        for w in frames.windows(2) {
+            assert!(w[1].sp > w[0].sp);
+        }
+    }
+
+    // Calls `f` with the registers of this function, as a parked stack.
+    #[inline(never)]
+    fn parked<F: FnOnce(Registers)>(f: F) {
+        let registers = Registers::current();
+        f(Registers::from_raw(registers.pc, registers.fp));
+    }
+
+    #[test]
+    fn test_trace_from_registers() {
+        parked(|registers| {
+            let mut parked = vec![];
+            trace_from_registers(&registers, |pc| {
+                parked.push(pc);
+                true
+            });
+            let mut all = vec![];
+            trace(|pc| {
+                all.push(pc);
+                true
+            });
+            assert_eq!(parked[0], registers.pc);
+            assert!(parked.len() > 1);
+            assert!(all.ends_with(&parked[1..]));
+        });
+        assert_eq!(Registers::from_raw(0x1000, 0x2000).sp, 0x2000 - RECORD_OFFSET);
+        let registers = Registers::new(1, 2, 3, 4);
+        assert_eq!((registers.pc, registers.fp, registers.sp, registers.lr), (1, 2, 3, 4));
+    }
+
+    #[test]
+    fn test_trace_outcome() {
+        assert_eq!(trace(|_| true), TraceOutcome::Completed);
+        assert_eq!(trace(|_| false), TraceOutcome::CallbackStopped);
+        let options = TraceOptions::new().max_depth(1);
+        assert_eq!(trace_with_options(&options, |_| true), TraceOutcome::MaxDepthReached);
+        assert_eq!(
+            trace_from_ucontext(std::ptr::null_mut(), |_| true),
+            TraceOutcome::NullUcontext
+        );
+        assert!(!TraceOutcome::MaxDepthReached.is_complete());
+    }
+
+    #[test]
+    fn test_trace_with_options() {
+        let mut all = vec![];
+        trace_frames(|frame| {
+            all.push(frame.pc);
+            true
+        });
+        assert!(all.len() > 3);
+
+        let mut frames = vec![];
+        trace_frames_with_options(&TraceOptions::new().skip(1).max_depth(2), |frame| {
+            frames.push(*frame);
+            true
+        });
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| !frame.is_top));
+
+        let mut frames = vec![];
+        trace_frames_with_options(&TraceOptions::new().adjust_pc(false), |frame| {
+            frames.push(*frame);
+            true
+        });
+        assert!(frames.iter().all(|frame| !frame.adjusted));
+        assert_eq!(frames.last().unwrap().pc, all.last().unwrap() + 1);
+
+        let mut n = 0;
+        trace_with_options(&TraceOptions::new().max_depth(0), |_| {
+            n += 1;
+            true
+        });
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_signal_trampoline() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static FOUND: AtomicBool = AtomicBool::new(false);
+
+        extern "C" fn handler(_: libc::c_int) {
+            let options = TraceOptions::new().signal_trampolines(true);
+            let mut found = false;
+            trace_frames_with_options(&options, |frame| {
+                found |= frame.is_signal_trampoline && !frame.adjusted;
+                true
+            });
+            FOUND.store(found, Ordering::SeqCst);
+        }
+
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()), 0);
+            assert_eq!(libc::raise(libc::SIGUSR2), 0);
+        }
+        assert!(FOUND.load(Ordering::SeqCst));
+
+        let mut frames = vec![];
+        trace_frames(|frame| {
+            frames.push(*frame);
+            true
+        });
+        assert!(frames.iter().all(|frame| !frame.is_signal_trampoline));
+    }
+
+    #[test]
+    #[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), target_os = "linux"))]
+    fn test_nested_signals() {
+        use std::sync::Mutex;
+
+        static FRAMES: Mutex<Vec<Frame>> = Mutex::new(Vec::new());
+
+        extern "C" fn inner(_: libc::c_int) {
+            let options = TraceOptions::new().signal_trampolines(true);
+            let mut frames = [None; 64];
+            let mut n = 0;
+            trace_frames_with_options(&options, |frame| {
+                frames[n] = Some(*frame);
+                n += 1;
+                n < frames.len()
+            });
+            FRAMES.lock().unwrap().extend(frames.iter().flatten());
+        }
+
+        extern "C" fn outer(_: libc::c_int) {
+            unsafe { libc::raise(libc::SIGURG) };
+        }
+
+        unsafe {
+            for (signal, handler) in [(libc::SIGUSR1, outer as extern "C" fn(_)), (libc::SIGURG, inner)] {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = handler as libc::sighandler_t;
+                assert_eq!(libc::sigaction(signal, &action, std::ptr::null_mut()), 0);
+            }
+        }
+        // The frame of the function calling `raise` itself is missing if libc
+        // is built without frame pointers, so call it from another one.
+        #[inline(never)]
+        fn raise() {
+            unsafe { libc::raise(libc::SIGUSR1) };
+        }
+        raise();
+        let frames = FRAMES.lock().unwrap();
+        let trampolines: Vec<_> = (0..frames.len()).filter(|n| frames[*n].is_signal_trampoline).collect();
+        assert_eq!(trampolines.len(), 2, "{:x?}", frames);
+        // Each trampoline is followed by the interrupted frame, as is.
+        for n in trampolines {
+            assert!(!frames[n + 1].adjusted && !frames[n + 1].is_top);
+            assert!(frames[n + 1].sp > frames[n].sp);
+        }
+        // The chain continues to the code that raised the first signal.
+        let symbols = crate::symbols::SymbolTable::load();
+        let test = symbols.resolve_symbol(test_nested_signals as fn() as usize as u64);
+        assert!(test.is_some());
+        assert!(frames.iter().any(|frame| symbols.resolve_symbol(frame.pc) == test));
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+    fn test_canonicalize() {
+        assert_eq!(canonicalize(0), Some(0));
+        assert_eq!(canonicalize(0xffff_ffff), Some(0xffff_ffff));
+        assert_eq!(canonicalize(0x1_0000_0000), None);
+    }
+
+    #[test]
+    fn test_is_aligned_pc() {
+        assert!(is_aligned_pc(0x1000, false));
+        assert_eq!(
+            is_aligned_pc(0x1001, false),
+            cfg!(any(target_arch = "x86_64", target_arch = "x86"))
+        );
+        let two = cfg!(any(
+            target_arch = "x86_64",
+            target_arch = "x86",
+            target_arch = "riscv64"
+        ));
+        assert_eq!(is_aligned_pc(0x1002, false), two);
+        assert_eq!(
+            split_thumb(0x1001),
+            (0x1001 & !(cfg!(target_arch = "arm") as u64), cfg!(target_arch = "arm"))
+        );
+        #[cfg(target_arch = "arm")]
+        assert!(is_aligned_pc(0x1002, true));
+    }
+
+    #[test]
+    fn test_check_fp() {
+        use cursor::FpRejection;
+        let val = 0u64;
+        assert_eq!(check_fp(&val as *const u64 as u64), Ok(()));
+        assert_eq!(check_fp(0), Err(FpRejection::Low));
+        assert_eq!(check_fp(MIN_FP - 1), Err(FpRejection::Low));
+        assert_eq!(check_fp(USER_SPACE_END), Err(FpRejection::Kernel));
+        assert_eq!(check_fp(u64::MAX), Err(FpRejection::Kernel));
+    }
 }
@@ -0,0 +1,248 @@
+//! Access to the memory of the call-stack being unwound.
+//!
+//! The walker reads frame records through a [`MemoryReader`]. The default one,
+//! [`LocalMemory`], reads the memory of the current process, other readers
+//! allow unwinding another process, a core dump, or a synthetic stack in a
+//! test with [`trace_with_reader`](crate::trace_with_reader).
+//!
+//! With the `memory-access-check` feature, [`LocalMemory`] reads frame records
+//! outside of the known stack bounds only after a [`MemoryValidator`]
+//! confirmed that they are readable. By default this is [`PipeValidator`],
+//! [`set_validator`] installs another one process-wide.
+//!
+//! ```rust
+//! # #[cfg(all(target_os = "linux", feature = "memory-access-check"))]
+//! tracefp::memory::set_validator(&tracefp::memory::ProcessVmValidator);
+//! ```
+
+use core::cell::Cell;
+#[cfg(feature = "memory-access-check")]
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::{load_unchecked, PageCache, StackBounds};
+
+/// Reads the memory of the call-stack being unwound.
+///
+/// Implementations used from signal handlers must be async-signal-safe.
+pub trait MemoryReader {
+    /// Reads the 8 bytes at `address` in native byte order, or returns `None`
+    /// if they are not readable.
+    fn read_u64(&self, address: u64) -> Option<u64>;
+
+    /// Reads the 4 bytes at `address` in native byte order, or returns `None`
+    /// if they are not readable. Only used on 32-bit targets.
+    ///
+    /// There is no default on top of [`read_u64`](MemoryReader::read_u64):
+    /// it would return the wrong half on big-endian targets, and fail for a
+    /// word at the very end of a mapping.
+    fn read_u32(&self, address: u64) -> Option<u32>;
+}
+
+impl<R: MemoryReader + ?Sized> MemoryReader for &R {
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        (**self).read_u64(address)
+    }
+
+    fn read_u32(&self, address: u64) -> Option<u32> {
+        (**self).read_u32(address)
+    }
+}
+
+/// Reads the memory of the current process.
+///
+/// Reads within the bounds of the stack being unwound are performed directly,
+/// all others are checked by the installed [`MemoryValidator`] first, unless
+/// the `memory-access-check` feature is disabled.
+#[derive(Debug, Clone, Default)]
+pub struct LocalMemory {
+    stack: Option<StackBounds>,
+    pages: Cell<PageCache>,
+}
+
+impl LocalMemory {
+    /// Creates a reader that checks every read.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Creates a reader that trusts reads within `stack`.
+    #[cfg(feature = "std")]
+    pub(crate) fn with_stack(stack: Option<StackBounds>) -> Self {
+        Self {
+            stack,
+            pages: Cell::new(PageCache::new()),
+        }
+    }
+
+    fn read<T: Copy>(&self, address: u64) -> Option<T> {
+        let size = core::mem::size_of::<T>() as u64;
+        if self.stack.is_some_and(|s| s.contains_range(address, size)) {
+            return unsafe { Some(load_unchecked(address)) };
+        }
+        // Check the last byte too, in case the value crosses into the next page.
+        let mut pages = self.pages.get();
+        let value = pages
+            .load::<u8>(address.checked_add(size - 1)?)
+            .and_then(|_| pages.load::<T>(address));
+        self.pages.set(pages);
+        value
+    }
+}
+
+impl MemoryReader for LocalMemory {
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        self.read(address)
+    }
+
+    fn read_u32(&self, address: u64) -> Option<u32> {
+        self.read(address)
+    }
+}
+
+/// Decides whether an address can be read without faulting.
+///
+/// Implementations are called from signal handlers and must be
+/// async-signal-safe.
+#[cfg(feature = "memory-access-check")]
+pub trait MemoryValidator: Sync {
+    /// Whether the byte at `address` is readable.
+    fn can_access(&self, address: u64) -> bool;
+}
+
+/// Validates addresses by letting the kernel read them into a pipe.
+///
+/// This works on every supported platform, but costs two syscalls per check
+/// and a pair of file descriptors per thread, which is created on first use.
+/// If no descriptors are left, every check fails.
+#[cfg(feature = "memory-access-check")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PipeValidator;
+
+#[cfg(feature = "memory-access-check")]
+impl MemoryValidator for PipeValidator {
+    fn can_access(&self, address: u64) -> bool {
+        crate::access_check::can_access(address)
+    }
+}
+
+/// Validates addresses by reading them with `process_vm_readv(2)`.
+///
+/// This needs no file descriptors. Where the syscall is unavailable (kernels
+/// before 3.2, or forbidden by a seccomp filter) it falls back to
+/// [`PipeValidator`].
+#[cfg(all(target_os = "linux", feature = "memory-access-check"))]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ProcessVmValidator;
+
+#[cfg(all(target_os = "linux", feature = "memory-access-check"))]
+impl MemoryValidator for ProcessVmValidator {
+    fn can_access(&self, address: u64) -> bool {
+        let mut byte = 0u8;
+        let local = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let remote = libc::iovec {
+            iov_base: address as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let size = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        if size == 1 {
+            return true;
+        }
+        match crate::access_check::errno() {
+            libc::ENOSYS | libc::EPERM => PipeValidator.can_access(address),
+            _ => false,
+        }
+    }
+}
+
+// The installed validator, null for the default `PipeValidator`.
+#[cfg(feature = "memory-access-check")]
+static VALIDATOR: AtomicPtr<&'static dyn MemoryValidator> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Use `validator` for all memory access checks from now on.
+///
+/// This is meant to be called once during startup. Every call leaks a
+/// pointer-sized allocation, since a signal handler may still be using the
+/// previous validator.
+#[cfg(feature = "memory-access-check")]
+pub fn set_validator(validator: &'static dyn MemoryValidator) {
+    VALIDATOR.store(Box::into_raw(Box::new(validator)), Ordering::Release);
+}
+
+// Check whether `address` is readable with the installed validator.
+#[cfg(feature = "memory-access-check")]
+pub(crate) fn can_access(address: u64) -> bool {
+    let validator = VALIDATOR.load(Ordering::Acquire);
+    if validator.is_null() {
+        PipeValidator.can_access(address)
+    } else {
+        unsafe { (*validator).can_access(address) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "memory-access-check")]
+    fn check(validator: &dyn MemoryValidator) {
+        let v1 = 1;
+        let v2 = Box::new(1);
+        assert!(validator.can_access(&v1 as *const i32 as u64));
+        assert!(validator.can_access(v2.as_ref() as *const i32 as u64));
+        assert!(!validator.can_access(0));
+        assert!(!validator.can_access(u64::MAX));
+    }
+
+    #[test]
+    fn test_local_memory() {
+        let vals = [0x0102_0304_0506_0708u64, u64::MAX];
+        let address = vals.as_ptr() as u64;
+        let reader = LocalMemory::new();
+        assert_eq!(reader.read_u64(address), Some(vals[0]));
+        assert_eq!(reader.read_u64(address + 4), Some(0xffff_ffff_0102_0304));
+        assert_eq!(reader.read_u32(address), Some(0x0506_0708));
+        #[cfg(feature = "memory-access-check")]
+        assert_eq!(reader.read_u64(0), None);
+
+        let stack = StackBounds::new(address, address + 16);
+        let reader = LocalMemory::with_stack(Some(stack));
+        assert_eq!(reader.read_u64(address + 8), Some(u64::MAX));
+        assert_eq!(reader.read_u64(address), Some(vals[0]));
+    }
+
+    #[test]
+    #[cfg(feature = "memory-access-check")]
+    fn test_validators() {
+        check(&PipeValidator);
+        #[cfg(target_os = "linux")]
+        check(&ProcessVmValidator);
+    }
+
+    #[test]
+    #[cfg(feature = "memory-access-check")]
+    fn test_set_validator() {
+        use std::sync::atomic::AtomicUsize;
+
+        // Other tests may run concurrently, so this must validate correctly.
+        struct Counting(AtomicUsize);
+
+        impl MemoryValidator for Counting {
+            fn can_access(&self, address: u64) -> bool {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                PipeValidator.can_access(address)
+            }
+        }
+
+        static COUNTING: Counting = Counting(AtomicUsize::new(0));
+
+        let v = 1;
+        set_validator(&COUNTING);
+        assert!(can_access(&v as *const i32 as u64));
+        assert!(!can_access(0));
+        set_validator(&PipeValidator);
+        assert!(COUNTING.0.load(Ordering::Relaxed) >= 2);
+    }
+}
@@ -0,0 +1,212 @@
+//! Merging of the profiles of several processes.
+//!
+//! The PCs in a [`Report`] are only meaningful in the process that took it,
+//! since every process loads its modules at different addresses. A
+//! [`MergedProfile`] turns the PCs of each report into offsets within their
+//! modules with the [`ModuleMap`] of its process, aligns the modules of all
+//! processes by build-id and sums the samples per stack, e.g. for one profile
+//! of all workers of a prefork server.
+//!
+//! ```rust
+//! use tracefp::merge::MergedProfile;
+//! use tracefp::modules::ModuleMap;
+//!
+//! let mut profile = MergedProfile::new();
+//! let profiler = tracefp::profiler::Profiler::start(99).unwrap();
+//! // ... the code to profile, in every worker ...
+//! let report = profiler.stop();
+//!
+//! // Usually the report and module map of each worker are sent to one
+//! // process, which merges them.
+//! profile.add(std::process::id(), &report, &ModuleMap::snapshot());
+//! for stack in profile.stacks() {
+//!     println!("{:?} {}", stack.stack, stack.count);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::modules::{Module, ModuleMap};
+use crate::profiler::Report;
+
+/// A frame of a merged stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Location {
+    /// A PC at `offset` in the module at index `module` of
+    /// [`MergedProfile::modules`], the address a symbolizer looks up in its
+    /// file.
+    Module { module: usize, offset: u64 },
+    /// A PC outside of all known modules, e.g. in JIT-compiled code, as is.
+    Unknown(u64),
+}
+
+/// The samples of one stack, summed over all processes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct MergedStack {
+    /// The frames of the stack, innermost first.
+    pub stack: Vec<Location>,
+    /// The number of samples taken with this stack in all processes.
+    pub count: u64,
+    /// The number of samples per tag passed to [`MergedProfile::add`],
+    /// sorted by tag.
+    pub counts: Vec<(u32, u64)>,
+    /// Whether the stack was truncated, see
+    /// [`ProfileStack::truncated`](crate::profiler::ProfileStack::truncated).
+    pub truncated: bool,
+}
+
+/// The profiles of several processes, merged into one.
+#[derive(Debug, Clone, Default)]
+pub struct MergedProfile {
+    // Without `bias`, which differs between processes.
+    modules: Vec<Module>,
+    // The modules by build-id, or by path if they have none.
+    index: HashMap<(Vec<u8>, Option<PathBuf>), usize>,
+    stacks: HashMap<(Vec<Location>, bool), HashMap<u32, u64>>,
+    dropped: u64,
+}
+
+impl MergedProfile {
+    /// Creates an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the samples of `report`, taken by a process with the modules in
+    /// `modules`, and tags them with `tag`, e.g. the pid of the process or the
+    /// index of a worker.
+    ///
+    /// Modules are the same in all processes if they have the same build-id,
+    /// modules without one if they have the same path.
+    pub fn add(&mut self, tag: u32, report: &Report, modules: &ModuleMap) {
+        for stack in &report.stacks {
+            let locations = stack.stack.iter().map(|pc| self.locate(*pc, modules)).collect();
+            let key = (locations, stack.truncated);
+            *self.stacks.entry(key).or_default().entry(tag).or_default() += stack.count;
+        }
+        self.dropped += report.dropped;
+    }
+
+    fn locate(&mut self, pc: u64, modules: &ModuleMap) -> Location {
+        let Some((module, offset)) = modules.lookup(pc) else {
+            return Location::Unknown(pc);
+        };
+        let key = match module.build_id.is_empty() {
+            true => (vec![], Some(module.path.clone())),
+            false => (module.build_id.clone(), None),
+        };
+        let next = self.modules.len();
+        let index = *self.index.entry(key).or_insert(next);
+        if index == next {
+            self.modules.push(Module {
+                bias: 0,
+                ..module.clone()
+            });
+        }
+        Location::Module { module: index, offset }
+    }
+
+    /// The modules the [`Location`]s refer to, with the path they were first
+    /// seen at and a `bias` of 0.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// The merged stacks, most frequent first.
+    pub fn stacks(&self) -> Vec<MergedStack> {
+        let mut stacks: Vec<_> = self
+            .stacks
+            .iter()
+            .map(|((stack, truncated), counts)| {
+                let mut counts: Vec<_> = counts.iter().map(|(tag, count)| (*tag, *count)).collect();
+                counts.sort_unstable();
+                MergedStack {
+                    stack: stack.clone(),
+                    count: counts.iter().map(|(_, count)| count).sum(),
+                    counts,
+                    truncated: *truncated,
+                }
+            })
+            .collect();
+        stacks.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.stack.cmp(&b.stack))
+                .then(a.truncated.cmp(&b.truncated))
+        });
+        stacks
+    }
+
+    /// The total number of samples in all reports.
+    pub fn samples(&self) -> u64 {
+        self.stacks.values().flat_map(|counts| counts.values()).sum()
+    }
+
+    /// The total number of samples the reports dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::ProfileStack;
+
+    #[test]
+    fn test_merge() {
+        let modules = ModuleMap::snapshot();
+        let pc = test_merge as fn() as usize as u64;
+        let (module, offset) = modules.lookup(pc).unwrap();
+        let base = Location::Module { module: 0, offset };
+
+        let report = |count, dropped| Report {
+            stacks: vec![
+                ProfileStack {
+                    stack: vec![pc, 1],
+                    count,
+                    truncated: false,
+                },
+                ProfileStack {
+                    stack: vec![pc],
+                    count: 1,
+                    truncated: true,
+                },
+            ],
+            dropped,
+            internal: vec![],
+        };
+        let mut profile = MergedProfile::new();
+        profile.add(7, &report(3, 1), &modules);
+        profile.add(2, &report(5, 2), &modules);
+        profile.add(7, &report(1, 0), &modules);
+
+        assert_eq!(profile.modules().len(), 1);
+        assert_eq!(profile.modules()[0].path, module.path);
+        assert_eq!(profile.modules()[0].bias, 0);
+        assert_eq!(profile.samples(), 12);
+        assert_eq!(profile.dropped(), 3);
+        assert_eq!(
+            profile.stacks(),
+            [
+                MergedStack {
+                    stack: vec![base, Location::Unknown(1)],
+                    count: 9,
+                    counts: vec![(2, 5), (7, 4)],
+                    truncated: false,
+                },
+                MergedStack {
+                    stack: vec![base],
+                    count: 3,
+                    counts: vec![(2, 1), (7, 2)],
+                    truncated: true,
+                },
+            ]
+        );
+    }
+}
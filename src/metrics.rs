@@ -0,0 +1,175 @@
+//! Cheap stack metrics that do not keep any PCs around.
+
+use std::cell::Cell;
+use std::ops::Range;
+
+/// Number of buckets in a [`DepthHistogram`].
+///
+/// Bucket `0` counts stacks of depth 0, bucket `i` counts depths in
+/// `2^(i-1)..2^i`, and the last bucket also absorbs everything deeper.
+pub const BUCKETS: usize = 24;
+
+thread_local! {
+    static DEPTHS: [Cell<u64>; BUCKETS] = const { [const { Cell::new(0) }; BUCKETS] };
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Distribution of stack depths recorded on one thread.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DepthHistogram {
+    counts: [u64; BUCKETS],
+    max: usize,
+}
+
+impl DepthHistogram {
+    /// Record one observation of `depth`.
+    pub fn record(&mut self, depth: usize) {
+        self.counts[bucket_of(depth)] += 1;
+        self.max = self.max.max(depth);
+    }
+
+    /// Total number of observations.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The deepest stack observed, 0 if nothing was recorded.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Iterate over the depth range and observation count of every bucket,
+    /// from the shallowest to the deepest.
+    pub fn buckets(&self) -> impl Iterator<Item = (Range<usize>, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(n, count)| (bucket_range(n), *count))
+    }
+}
+
+/// Returns the number of frames on the current call-stack, including the
+/// frames of tracefp itself.
+pub fn depth() -> usize {
+    let mut depth = 0;
+    crate::trace(|_| {
+        depth += 1;
+        true
+    });
+    depth
+}
+
+/// Returns the number of frames on the call-stack from `ucontext`.
+pub fn depth_from_ucontext(ucontext: *mut libc::c_void) -> usize {
+    let mut depth = 0;
+    crate::trace_from_ucontext(ucontext, |_| {
+        depth += 1;
+        true
+    });
+    depth
+}
+
+/// Record the depth of the current call-stack into the calling thread's
+/// histogram.
+pub fn record_depth() {
+    record(depth());
+}
+
+/// Record the depth of the call-stack from `ucontext` into the calling
+/// thread's histogram.
+///
+/// This is intended to be called from a periodic signal handler (e.g.
+/// SIGPROF), it neither allocates nor takes locks.
+pub fn record_depth_from_ucontext(ucontext: *mut libc::c_void) {
+    record(depth_from_ucontext(ucontext));
+}
+
+/// Returns a snapshot of the calling thread's depth histogram.
+pub fn depth_histogram() -> DepthHistogram {
+    let mut histogram = DepthHistogram {
+        max: MAX_DEPTH.with(|v| v.get()),
+        ..Default::default()
+    };
+    DEPTHS.with(|depths| {
+        for (count, depth) in histogram.counts.iter_mut().zip(depths) {
+            *count = depth.get();
+        }
+    });
+    histogram
+}
+
+/// Clear the calling thread's depth histogram.
+pub fn reset_depth_histogram() {
+    DEPTHS.with(|depths| depths.iter().for_each(|v| v.set(0)));
+    MAX_DEPTH.with(|v| v.set(0));
+}
+
+// Every bucket is a separate cell so that a signal handler interrupting an
+// update on the same thread can at worst lose its own increment.
+fn record(depth: usize) {
+    DEPTHS.with(|depths| {
+        let count = &depths[bucket_of(depth)];
+        count.set(count.get() + 1);
+    });
+    MAX_DEPTH.with(|v| v.set(v.get().max(depth)));
+}
+
+#[inline]
+fn bucket_of(depth: usize) -> usize {
+    let n = (usize::BITS - depth.leading_zeros()) as usize;
+    n.min(BUCKETS - 1)
+}
+
+fn bucket_range(n: usize) -> Range<usize> {
+    let start = if n == 0 { 0 } else { 1 << (n - 1) };
+    let end = if n == BUCKETS - 1 { usize::MAX } else { 1 << n };
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_of() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_of(1), 1);
+        assert_eq!(bucket_of(2), 2);
+        assert_eq!(bucket_of(3), 2);
+        assert_eq!(bucket_of(4), 3);
+        assert_eq!(bucket_of(usize::MAX), BUCKETS - 1);
+        for n in 0..BUCKETS {
+            let range = bucket_range(n);
+            assert_eq!(bucket_of(range.start), n);
+            assert_eq!(bucket_of(range.end - 1), n);
+        }
+    }
+
+    #[test]
+    fn test_depth_histogram() {
+        reset_depth_histogram();
+        record(3);
+        record(3);
+        record(100);
+        let histogram = depth_histogram();
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.max(), 100);
+        assert!(histogram
+            .buckets()
+            .any(|(range, count)| range.contains(&3) && count == 2));
+        assert!(histogram
+            .buckets()
+            .any(|(range, count)| range.contains(&100) && count == 1));
+
+        let mut local = DepthHistogram::default();
+        local.record(3);
+        local.record(3);
+        local.record(100);
+        assert_eq!(local, histogram);
+
+        record_depth();
+        assert_eq!(depth_histogram().count(), 4);
+        reset_depth_histogram();
+        assert_eq!(depth_histogram(), DepthHistogram::default());
+    }
+}
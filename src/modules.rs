@@ -0,0 +1,389 @@
+//! Mapping of PCs to the modules (executable and shared libraries) they
+//! belong to.
+//!
+//! Symbolizing a trace in the process that captured it is expensive and
+//! needs the debug info on the same machine. Instead, a [`ModuleMap`] taken
+//! once outside of the signal handler turns each PC into its module and an
+//! offset within it, which together with the module's build-id is all a
+//! backend needs to symbolize the trace offline.
+//!
+//! ```rust
+//! let modules = tracefp::modules::ModuleMap::snapshot();
+//! tracefp::trace(|pc| {
+//!     if let Some((module, offset)) = modules.lookup(pc) {
+//!         println!("{}+{:#x}", module.path.display(), offset);
+//!     }
+//!     true
+//! });
+//! ```
+//!
+//! Modules loaded after the snapshot are unknown to it, take a new one after
+//! `dlopen`.
+
+use std::path::PathBuf;
+
+/// A module loaded into the process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// The path the module was loaded from.
+    pub path: PathBuf,
+    /// The GNU build-id of an ELF module, or the `LC_UUID` of a Mach-O one.
+    /// Empty if the module has none.
+    pub build_id: Vec<u8>,
+    /// The difference between the addresses of the module in memory and
+    /// the addresses in its file.
+    pub bias: u64,
+}
+
+/// A snapshot of the modules of the current process.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleMap {
+    modules: Vec<Module>,
+    // The executable segments as `(start, end, module)`, sorted by `start`.
+    ranges: Vec<(u64, u64, usize)>,
+}
+
+impl ModuleMap {
+    /// Takes a snapshot of the modules currently loaded. Must not be called
+    /// from a signal handler.
+    pub fn snapshot() -> Self {
+        let mut map = Self::default();
+        load(&mut map);
+        map.ranges.sort_unstable();
+        map
+    }
+
+    /// The modules in the snapshot.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Finds the module whose code contains `pc`, and the offset of `pc` in
+    /// it, which is the address a symbolizer looks up in the module's file.
+    ///
+    /// This does not allocate or take locks, so it can be called from a
+    /// signal handler.
+    pub fn lookup(&self, pc: u64) -> Option<(&Module, u64)> {
+        let n = self.ranges.partition_point(|r| r.0 <= pc).checked_sub(1)?;
+        let (_, end, module) = self.ranges[n];
+        if pc >= end {
+            return None;
+        }
+        let module = &self.modules[module];
+        Some((module, pc.wrapping_sub(module.bias)))
+    }
+
+    /// Reads the modules of process `pid` from `/proc/<pid>/maps` and their
+    /// files. Linux only.
+    ///
+    /// Unlike [`snapshot`](ModuleMap::snapshot) this only knows modules that
+    /// are mapped from files, and falls back to a bias derived from the
+    /// mapping alone for files that cannot be read.
+    #[cfg(target_os = "linux")]
+    pub fn for_process(pid: libc::pid_t) -> std::io::Result<Self> {
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))?;
+        let mut map = Self::default();
+        let mut indexes = std::collections::HashMap::new();
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64 - 1;
+        for line in maps.lines() {
+            // start-end perms offset dev inode path
+            let mut fields = line.split_whitespace();
+            let (Some(range), Some(perms), Some(offset)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let path = fields.nth(2).unwrap_or_default();
+            if !perms.contains('x') || !path.starts_with('/') {
+                continue;
+            }
+            let Some((start, end)) = range.split_once('-') else {
+                continue;
+            };
+            let parse = |v: &str| u64::from_str_radix(v, 16).ok();
+            let (Some(start), Some(end), Some(offset)) = (parse(start), parse(end), parse(offset)) else {
+                continue;
+            };
+            let index = *indexes.entry(path.to_string()).or_insert_with(|| {
+                let data = std::fs::read(path).unwrap_or_default();
+                let segments = elf_segments(&data).unwrap_or_default();
+                // The executable segment the mapping starts in, which is
+                // mapped from the page its file offset is in.
+                let bias = segments
+                    .iter()
+                    .filter(|s| s.kind == libc::PT_LOAD && s.flags & libc::PF_X != 0)
+                    .find(|s| s.offset & !page <= offset && offset < s.offset + s.size.max(1))
+                    .map_or(start.wrapping_sub(offset), |s| {
+                        start
+                            .wrapping_sub(s.address & !page)
+                            .wrapping_sub(offset - (s.offset & !page))
+                    });
+                let build_id = segments
+                    .iter()
+                    .filter(|s| s.kind == libc::PT_NOTE)
+                    .find_map(|s| build_id(data.get(s.offset as usize..(s.offset + s.size) as usize)?))
+                    .unwrap_or_default();
+                map.modules.push(Module {
+                    path: PathBuf::from(path),
+                    build_id,
+                    bias,
+                });
+                map.modules.len() - 1
+            });
+            map.ranges.push((start, end, index));
+        }
+        map.ranges.sort_unstable();
+        Ok(map)
+    }
+
+    fn push(&mut self, module: Module, ranges: impl Iterator<Item = (u64, u64)>) {
+        let index = self.modules.len();
+        self.ranges.extend(ranges.map(|(start, end)| (start, end, index)));
+        self.modules.push(module);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn load(map: &mut ModuleMap) {
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _: libc::size_t,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        let info = &*info;
+        let map = &mut *(data as *mut ModuleMap);
+        let bias = info.dlpi_addr as usize as u64;
+        let phdrs = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+        let name = if info.dlpi_name.is_null() {
+            &[][..]
+        } else {
+            std::ffi::CStr::from_ptr(info.dlpi_name).to_bytes()
+        };
+        // The executable itself has no name.
+        let path = if name.is_empty() {
+            std::env::current_exe().unwrap_or_default()
+        } else {
+            PathBuf::from(std::ffi::OsStr::from_encoded_bytes_unchecked(name))
+        };
+        let build_id = phdrs
+            .iter()
+            .filter(|p| p.p_type == libc::PT_NOTE)
+            .find_map(|p| {
+                let address = bias + p.p_vaddr as usize as u64;
+                build_id(std::slice::from_raw_parts(address as *const u8, p.p_memsz as usize))
+            })
+            .unwrap_or_default();
+        let ranges = phdrs
+            .iter()
+            .filter(|p| p.p_type == libc::PT_LOAD && p.p_flags & libc::PF_X != 0)
+            .map(|p| {
+                (
+                    bias + p.p_vaddr as usize as u64,
+                    bias + p.p_vaddr as usize as u64 + p.p_memsz as usize as u64,
+                )
+            });
+        map.push(Module { path, build_id, bias }, ranges);
+        0
+    }
+
+    unsafe { libc::dl_iterate_phdr(Some(callback), map as *mut ModuleMap as *mut libc::c_void) };
+}
+
+// A program header of an ELF file.
+#[cfg(target_os = "linux")]
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    size: u64,
+}
+
+// The program headers of the ELF file `data` of the native class.
+#[cfg(target_os = "linux")]
+fn elf_segments(data: &[u8]) -> Option<Vec<Segment>> {
+    const WORD: usize = std::mem::size_of::<usize>();
+    let u16_at = |offset: usize| Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?));
+    let u32_at = |offset: usize| Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?));
+    let word_at = |offset: usize| {
+        let mut word = [0u8; 8];
+        word[..WORD].copy_from_slice(data.get(offset..offset + WORD)?);
+        Some(u64::from_le_bytes(word))
+    };
+    if data.get(..4)? != b"\x7fELF" || *data.get(4)? as usize * 4 != WORD {
+        return None;
+    }
+    let (phoff, phentsize, phnum) = match WORD {
+        8 => (word_at(32)?, u16_at(54)?, u16_at(56)?),
+        _ => (word_at(28)?, u16_at(42)?, u16_at(44)?),
+    };
+    (0..phnum as usize)
+        .map(|n| {
+            let phdr = phoff as usize + n * phentsize as usize;
+            match WORD {
+                8 => Some(Segment {
+                    kind: u32_at(phdr)?,
+                    flags: u32_at(phdr + 4)?,
+                    offset: word_at(phdr + 8)?,
+                    address: word_at(phdr + 16)?,
+                    size: word_at(phdr + 32)?,
+                }),
+                _ => Some(Segment {
+                    kind: u32_at(phdr)?,
+                    flags: u32_at(phdr + 24)?,
+                    offset: word_at(phdr + 4)?,
+                    address: word_at(phdr + 8)?,
+                    size: word_at(phdr + 16)?,
+                }),
+            }
+        })
+        .collect()
+}
+
+// Find the `NT_GNU_BUILD_ID` note among `notes`.
+#[cfg(target_os = "linux")]
+fn build_id(notes: &[u8]) -> Option<Vec<u8>> {
+    const NT_GNU_BUILD_ID: u32 = 3;
+    let u32_at = |offset: usize| Some(u32::from_ne_bytes(notes.get(offset..offset + 4)?.try_into().ok()?));
+    let align4 = |n: usize| (n + 3) & !3;
+    let mut note = 0;
+    while note + 12 <= notes.len() {
+        let namesz = u32_at(note)? as usize;
+        let descsz = u32_at(note + 4)? as usize;
+        let name = note + 12;
+        let desc = name + align4(namesz);
+        if u32_at(note + 8)? == NT_GNU_BUILD_ID && notes.get(name..name + namesz) == Some(b"GNU\0") {
+            return Some(notes.get(desc..desc + descsz)?.to_vec());
+        }
+        note = desc + align4(descsz);
+    }
+    None
+}
+
+// Declared here, since the declarations in libc are deprecated.
+#[cfg(target_os = "macos")]
+extern "C" {
+    pub(crate) fn _dyld_image_count() -> u32;
+    pub(crate) fn _dyld_get_image_header(index: u32) -> *const u8;
+    pub(crate) fn _dyld_get_image_vmaddr_slide(index: u32) -> isize;
+    pub(crate) fn _dyld_get_image_name(index: u32) -> *const libc::c_char;
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) const LC_SEGMENT_64: u32 = 0x19;
+
+// The load commands of the image whose `mach_header_64` is at `header`.
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn load_commands(header: *const u8) -> impl Iterator<Item = *const u8> {
+    // The commands follow the 32-byte header.
+    let mut command = header.add(32);
+    (0..read_u32(header.add(16))).map(move |_| {
+        let current = command;
+        command = command.add(read_u32(command.add(4)) as usize);
+        current
+    })
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn read_u32(address: *const u8) -> u32 {
+    (address as *const u32).read_unaligned()
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn read_u64(address: *const u8) -> u64 {
+    (address as *const u64).read_unaligned()
+}
+
+#[cfg(target_os = "macos")]
+fn load(map: &mut ModuleMap) {
+    const LC_UUID: u32 = 0x1b;
+    const VM_PROT_EXECUTE: u32 = 0x4;
+
+    unsafe {
+        for n in 0.._dyld_image_count() {
+            let header = _dyld_get_image_header(n);
+            let name = _dyld_get_image_name(n);
+            if header.is_null() || name.is_null() {
+                continue;
+            }
+            let bias = _dyld_get_image_vmaddr_slide(n) as u64;
+            let path = PathBuf::from(std::ffi::OsStr::from_encoded_bytes_unchecked(
+                std::ffi::CStr::from_ptr(name).to_bytes(),
+            ));
+            let mut build_id = vec![];
+            let mut ranges = vec![];
+            for command in load_commands(header) {
+                match read_u32(command) {
+                    LC_UUID => build_id = std::slice::from_raw_parts(command.add(8), 16).to_vec(),
+                    LC_SEGMENT_64 if read_u32(command.add(60)) & VM_PROT_EXECUTE != 0 => {
+                        let start = read_u64(command.add(24)).wrapping_add(bias);
+                        ranges.push((start, start + read_u64(command.add(32))));
+                    }
+                    _ => {}
+                }
+            }
+            map.push(Module { path, build_id, bias }, ranges.into_iter());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_map() {
+        let modules = ModuleMap::snapshot();
+        assert!(!modules.modules().is_empty());
+        assert!(modules.lookup(0).is_none());
+
+        let pc = test_module_map as fn() as usize as u64;
+        let (module, offset) = modules.lookup(pc).unwrap();
+        assert_eq!(module.path, std::env::current_exe().unwrap());
+        assert_eq!(offset, pc - module.bias);
+
+        let pc = libc::getpid as unsafe extern "C" fn() -> libc::pid_t as usize as u64;
+        let (module, _) = modules.lookup(pc).unwrap();
+        assert_ne!(module.path, std::env::current_exe().unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_for_process() {
+        let snapshot = ModuleMap::snapshot();
+        let modules = ModuleMap::for_process(unsafe { libc::getpid() }).unwrap();
+        for pc in [
+            test_for_process as fn() as usize as u64,
+            libc::getpid as unsafe extern "C" fn() -> libc::pid_t as usize as u64,
+        ] {
+            let (expected, offset) = snapshot.lookup(pc).unwrap();
+            let (module, _) = modules.lookup(pc).unwrap();
+            assert_eq!(module.bias, expected.bias);
+            assert_eq!(module.build_id, expected.build_id);
+            assert_eq!(modules.lookup(pc).unwrap().1, offset);
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut modules = ModuleMap::default();
+        let module = |n: u64| Module {
+            path: PathBuf::from(format!("lib{}.so", n)),
+            build_id: vec![n as u8],
+            bias: n * 0x1000,
+        };
+        modules.push(module(1), [(0x1000, 0x1800), (0x1900, 0x2000)].into_iter());
+        modules.push(module(4), [(0x4000, 0x5000)].into_iter());
+        modules.ranges.sort_unstable();
+        assert_eq!(
+            modules.lookup(0x1234).map(|(m, o)| (m.build_id[0], o)),
+            Some((1, 0x234))
+        );
+        assert_eq!(
+            modules.lookup(0x1fff).map(|(m, o)| (m.build_id[0], o)),
+            Some((1, 0xfff))
+        );
+        assert_eq!(modules.lookup(0x4000).map(|(m, o)| (m.build_id[0], o)), Some((4, 0)));
+        assert!(modules.lookup(0x1800).is_none());
+        assert!(modules.lookup(0x5000).is_none());
+        assert!(modules.lookup(0xfff).is_none());
+    }
+}
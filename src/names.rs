@@ -0,0 +1,256 @@
+//! Normalization of demangled symbol names.
+//!
+//! Generic instantiations produce many distinct names for what is logically one
+//! function, e.g. `core::ptr::drop_in_place<alloc::vec::Vec<u8>>::h0123456789abcdef`
+//! and `core::ptr::drop_in_place<alloc::string::String>::hfedcba9876543210`. When
+//! stacks are aggregated by name these fragment a flamegraph into many thin towers.
+//! [`Normalizer`] maps such names onto one canonical name, here both become
+//! `core::ptr::drop_in_place`. The input is left untouched so that callers can keep
+//! both the raw and the normalized name.
+//!
+//! Reports hold PCs, their names are chosen by the closure passed to e.g.
+//! [`Report::folded`](crate::profiler::Report::folded), which can aggregate by
+//! the normalized name and keep the raw ones aside:
+//!
+//! ```rust
+//! use std::collections::{BTreeSet, HashMap};
+//! use tracefp::names::Normalizer;
+//!
+//! # let report = tracefp::profiler::Report::default();
+//! # let resolve = |pc: u64| format!("{:#x}", pc);
+//! let normalizer = Normalizer::new();
+//! let mut raw_names: HashMap<String, BTreeSet<String>> = HashMap::new();
+//! let mut folded = vec![];
+//! report
+//!     .folded(&mut folded, |pc| {
+//!         let raw = resolve(pc);
+//!         let normalized = normalizer.normalize(&raw);
+//!         raw_names.entry(normalized.clone()).or_default().insert(raw);
+//!         normalized
+//!     })
+//!     .unwrap();
+//! ```
+
+/// Rewrites demangled Rust and C++ symbol names into canonical names.
+///
+/// ```rust
+/// use tracefp::names::Normalizer;
+///
+/// let normalizer = Normalizer::new();
+/// assert_eq!(normalizer.normalize("hello::func2::h53002ef4ebe4d7d7"), "hello::func2");
+/// assert_eq!(
+///     normalizer.normalize("<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop"),
+///     "<alloc::vec::Vec as core::ops::drop::Drop>::drop",
+/// );
+/// assert_eq!(
+///     normalizer.normalize("std::vector<int, std::allocator<int> >::push_back(int const&)"),
+///     "std::vector::push_back(int const&)",
+/// );
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct Normalizer {
+    strip_hash: bool,
+    strip_generics: bool,
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Normalizer {
+    /// Creates a normalizer that strips both hashes and generic parameters.
+    pub fn new() -> Self {
+        Self {
+            strip_hash: true,
+            strip_generics: true,
+        }
+    }
+
+    /// Whether to strip Rust symbol hashes: the legacy `::h<16 hex digits>`
+    /// suffix and v0 crate disambiguators such as `core[846817f741e54dfd]`.
+    pub fn strip_hash(mut self, strip: bool) -> Self {
+        self.strip_hash = strip;
+        self
+    }
+
+    /// Whether to strip generic parameter lists (Rust generics, turbofish and
+    /// C++ template arguments).
+    ///
+    /// Qualified paths such as `<T as Trait>::f` and `<impl Trait for T>::f` are
+    /// kept, only the parameter lists inside them are stripped.
+    pub fn strip_generics(mut self, strip: bool) -> Self {
+        self.strip_generics = strip;
+        self
+    }
+
+    /// Returns the normalized form of the demangled symbol `name`.
+    pub fn normalize(&self, name: &str) -> String {
+        let mut name = name;
+        if self.strip_hash {
+            name = strip_legacy_hash(name);
+        }
+        let mut out = String::with_capacity(name.len());
+        // One entry per open '<': whether the group is kept in the output.
+        let mut groups: Vec<bool> = Vec::new();
+        let mut chars = name.char_indices();
+        let mut prev = None;
+        while let Some((n, c)) = chars.next() {
+            let stripping = groups.contains(&false);
+            // The '>' of `->`, as in `fn() -> u8`, closes no group.
+            let arrow = c == '>' && prev == Some('-');
+            prev = Some(c);
+            match c {
+                '<' | '>' | '=' | '-' if !stripping && is_operator(&out) => out.push(c),
+                '<' if self.strip_generics => {
+                    let keep = !stripping && is_qualified_path(&out, &name[n + 1..]);
+                    if !keep && !stripping && out.ends_with("::") {
+                        out.truncate(out.len() - 2);
+                    }
+                    if keep {
+                        out.push(c);
+                    }
+                    groups.push(keep);
+                }
+                '>' if self.strip_generics && !arrow => {
+                    // A '>' without an open group is dropped.
+                    if groups.pop() == Some(true) {
+                        out.push(c);
+                    }
+                }
+                '[' if self.strip_hash && !stripping => {
+                    let hex = name[n + 1..]
+                        .find(']')
+                        .filter(|end| *end >= 8 && name[n + 1..n + 1 + end].bytes().all(|b| b.is_ascii_hexdigit()));
+                    match hex {
+                        Some(end) => {
+                            for _ in 0..=end {
+                                chars.next();
+                            }
+                        }
+                        None => out.push(c),
+                    }
+                }
+                _ if stripping => {}
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+// Strip the trailing `::h<16 hex digits>` emitted by the legacy Rust mangling.
+fn strip_legacy_hash(name: &str) -> &str {
+    if let Some(n) = name.rfind("::h") {
+        let hash = &name[n + 3..];
+        if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return &name[..n];
+        }
+    }
+    name
+}
+
+// Whether the output so far ends with an operator name, in which case '<',
+// '>', '=' and '-' are part of the name (`operator<<`, `operator->`, ...).
+fn is_operator(out: &str) -> bool {
+    let out = out.trim_end_matches(['<', '>', '=', '-']);
+    out.ends_with("operator")
+}
+
+// Whether a '<' starts a qualified path (`<T as Trait>`, `<impl Trait for T>`)
+// rather than a generic parameter list. `rest` is the text following the '<'.
+fn is_qualified_path(out: &str, rest: &str) -> bool {
+    if rest.starts_with("impl ") {
+        return true;
+    }
+    match out.chars().last() {
+        None => true,
+        Some(c) => !(c.is_alphanumeric() || c == '_' || c == ':' || c == '}'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_rust() {
+        let n = Normalizer::new();
+        assert_eq!(n.normalize("hello::main::h994e0b3179971102"), "hello::main");
+        assert_eq!(
+            n.normalize("std::rt::lang_start::{{closure}}::ha86392d061932837"),
+            "std::rt::lang_start::{{closure}}"
+        );
+        assert_eq!(
+            n.normalize("core::ptr::drop_in_place<alloc::vec::Vec<u8>>"),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(n.normalize("foo::bar::<u32>"), "foo::bar");
+        assert_eq!(
+            n.normalize("core::ops::function::impls::<impl core::ops::function::FnOnce<A> for &F>::call_once"),
+            "core::ops::function::impls::<impl core::ops::function::FnOnce for &F>::call_once"
+        );
+        assert_eq!(
+            n.normalize("<core[846817f741e54dfd]::option::Option<u8>>::unwrap"),
+            "<core::option::Option>::unwrap"
+        );
+        assert_eq!(n.normalize("short::h123"), "short::h123");
+        assert_eq!(n.normalize("a<b>>c"), "ac");
+    }
+
+    #[test]
+    fn test_normalize_arrows() {
+        let n = Normalizer::new();
+        // Closures.
+        assert_eq!(
+            n.normalize("core::ptr::drop_in_place<tracefp::profiler::collect::{{closure}}>"),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(
+            n.normalize("<F as core::ops::function::FnOnce<(&u8,)>>::call_once::{{closure}}::h0123456789abcdef"),
+            "<F as core::ops::function::FnOnce>::call_once::{{closure}}"
+        );
+        // `fn` pointers.
+        assert_eq!(
+            n.normalize("core::ptr::drop_in_place<fn() -> u8>::h0123456789abcdef"),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(
+            n.normalize("<fn(&u8) -> bool as core::ops::function::FnOnce<(&u8,)>>::call_once"),
+            "<fn(&u8) -> bool as core::ops::function::FnOnce>::call_once"
+        );
+        // `dyn Fn` types.
+        assert_eq!(
+            n.normalize("<alloc::boxed::Box<dyn Fn() -> u8> as core::ops::drop::Drop>::drop"),
+            "<alloc::boxed::Box as core::ops::drop::Drop>::drop"
+        );
+        assert_eq!(
+            n.normalize("foo::bar<alloc::boxed::Box<dyn core::ops::function::Fn(u8) -> Option<u8>>>::baz"),
+            "foo::bar::baz"
+        );
+    }
+
+    #[test]
+    fn test_normalize_cpp() {
+        let n = Normalizer::new();
+        assert_eq!(
+            n.normalize("std::map<int, std::vector<int> >::find(int const&)"),
+            "std::map::find(int const&)"
+        );
+        assert_eq!(n.normalize("Foo::operator<<(int)"), "Foo::operator<<(int)");
+        assert_eq!(n.normalize("Foo<int>::operator->()"), "Foo::operator->()");
+        assert_eq!(n.normalize("arr[3]"), "arr[3]");
+    }
+
+    #[test]
+    fn test_normalize_options() {
+        let name = "core::ptr::drop_in_place<u8>::h0123456789abcdef";
+        let n = Normalizer::new().strip_hash(false);
+        assert_eq!(n.normalize(name), "core::ptr::drop_in_place::h0123456789abcdef");
+        let n = Normalizer::new().strip_generics(false);
+        assert_eq!(n.normalize(name), "core::ptr::drop_in_place<u8>");
+        let n = Normalizer::new().strip_hash(false).strip_generics(false);
+        assert_eq!(n.normalize(name), name);
+    }
+}
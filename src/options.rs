@@ -0,0 +1,219 @@
+use crate::StackBounds;
+
+/// Options controlling how a call-stack is unwound.
+///
+/// ```rust
+/// use tracefp::TraceOptions;
+///
+/// // Hide the two innermost frames and report at most 64 frames.
+/// let options = TraceOptions::new().skip(2).max_depth(64);
+/// tracefp::trace_with_options(&options, |pc| {
+///     println!("{:#x}", pc);
+///     true
+/// });
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceOptions {
+    pub(crate) max_depth: usize,
+    pub(crate) max_stack_bytes: usize,
+    pub(crate) skip: usize,
+    pub(crate) adjust_pc: bool,
+    pub(crate) hardened: bool,
+    pub(crate) signal_trampolines: bool,
+    pub(crate) link_register: bool,
+    pub(crate) check_pc_alignment: bool,
+    pub(crate) fp_check: Option<FpCheck>,
+    pub(crate) stack_bounds: StackBoundsMode,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum StackBoundsMode {
+    Auto,
+    Fixed(StackBounds),
+    Disabled,
+}
+
+// The predicate of `TraceOptions::fp_check`, compared by address.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct FpCheck(pub(crate) fn(u64) -> bool);
+
+impl PartialEq for FpCheck {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::fn_addr_eq(self.0, other.0)
+    }
+}
+
+impl Eq for FpCheck {}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceOptions {
+    /// Creates the default options: no depth limit, no skipped frames,
+    /// return-address adjustment, hardened mode and the link register enabled.
+    pub fn new() -> Self {
+        Self {
+            max_depth: usize::MAX,
+            max_stack_bytes: usize::MAX,
+            skip: 0,
+            adjust_pc: true,
+            hardened: true,
+            signal_trampolines: false,
+            link_register: true,
+            check_pc_alignment: false,
+            fp_check: None,
+            stack_bounds: StackBoundsMode::Auto,
+        }
+    }
+
+    /// Report at most `n` frames, not counting the skipped ones.
+    pub fn max_depth(mut self, n: usize) -> Self {
+        self.max_depth = n;
+        self
+    }
+
+    /// Read no frame record that ends more than `n` bytes above the stack
+    /// pointer of the topmost frame. Unlimited by default.
+    ///
+    /// Unlike [`max_depth`](TraceOptions::max_depth) this bounds the stack
+    /// memory a walk touches, and with it the cache pollution and page faults
+    /// when unwinding threads with frames of enormous size. The walk ends with
+    /// [`StopReason::StackLimitReached`](crate::StopReason::StackLimitReached),
+    /// [`UnwindCursor::stack_bytes`](crate::UnwindCursor::stack_bytes) reports
+    /// the amount spanned.
+    pub fn max_stack_bytes(mut self, n: usize) -> Self {
+        self.max_stack_bytes = n;
+        self
+    }
+
+    /// Do not report the `n` innermost frames, e.g. to hide the frames of
+    /// tracefp itself or of a profiler built on top of it.
+    pub fn skip(mut self, n: usize) -> Self {
+        self.skip = n;
+        self
+    }
+
+    /// Whether to subtract 1 from return addresses so that they point into the
+    /// call instruction, see [`Frame::pc`](crate::Frame::pc). Enabled by default.
+    pub fn adjust_pc(mut self, adjust: bool) -> Self {
+        self.adjust_pc = adjust;
+        self
+    }
+
+    /// Whether to run additional sanity checks on every frame record before it
+    /// is followed. Enabled by default.
+    ///
+    /// In hardened mode the walk terminates with a
+    /// [`StopReason`](crate::StopReason) as soon as a frame pointer is
+    /// misaligned, does not move towards the base of the stack (which would
+    /// otherwise make a corrupted chain loop forever), or an absolute frame
+    /// limit far beyond any real call-stack is reached.
+    ///
+    /// Turn it off only for stacks that legitimately violate these rules, e.g.
+    /// chains that switch between separately allocated stacks.
+    pub fn hardened(mut self, hardened: bool) -> Self {
+        self.hardened = hardened;
+        self
+    }
+
+    /// Whether to recognize the signal trampoline when unwinding through a
+    /// signal frame and flag it with
+    /// [`Frame::is_signal_trampoline`](crate::Frame::is_signal_trampoline).
+    /// Disabled by default.
+    ///
+    /// The check reads the code at every return address, which costs two more
+    /// memory access checks per frame with the `memory-access-check` feature.
+    /// Only Linux trampolines are recognized. On x86_64 and i686 the walk
+    /// continues below a trampoline with the context saved in the signal
+    /// frame, see [`Frame::is_signal_trampoline`](crate::Frame::is_signal_trampoline).
+    pub fn signal_trampolines(mut self, detect: bool) -> Self {
+        self.signal_trampolines = detect;
+        self
+    }
+
+    /// Whether to report the link register of the topmost frame as its caller
+    /// on aarch64. Enabled by default, ignored on other architectures.
+    ///
+    /// A leaf function that does not set up a frame record keeps its return
+    /// address in the link register only, so the frame-pointer chain skips its
+    /// caller. The link register is reported as the second frame unless it
+    /// equals the topmost PC or the return address of the first frame record.
+    /// Right after a function returned into a non-leaf function the register
+    /// still holds that stale return address, which then shows up as a
+    /// spurious frame; disable this if such frames are a problem.
+    pub fn link_register(mut self, enable: bool) -> Self {
+        self.link_register = enable;
+        self
+    }
+
+    /// Whether to end the walk with
+    /// [`StopReason::InvalidFramePointer`](crate::StopReason::InvalidFramePointer)
+    /// at a return address no instruction can start at. Disabled by default.
+    ///
+    /// Instructions are 4-byte aligned on aarch64, loongarch64 and in ARM
+    /// state, 2-byte aligned on riscv64 and in Thumb state, and unaligned on
+    /// x86. A misaligned return address is a sure sign of a frame record that
+    /// holds something else, which would otherwise be reported as a frame.
+    pub fn check_pc_alignment(mut self, check: bool) -> Self {
+        self.check_pc_alignment = check;
+        self
+    }
+
+    /// Decide with `check` which frame pointers can point to a frame record,
+    /// instead of accepting the user-space addresses above the first page.
+    ///
+    /// `check` gets the frame pointer with the address tag stripped on
+    /// aarch64, and its rejections end the walk with
+    /// [`StopReason::InvalidFramePointer`](crate::StopReason::InvalidFramePointer)
+    /// before anything is read. They are counted in
+    /// [`FpRejections::custom`](crate::FpRejections::custom). A frame pointer
+    /// of 0 always ends the chain. Where the stack bounds are known, see
+    /// [`stack_bounds`](TraceOptions::stack_bounds), `check` only gets the
+    /// frame pointers within them.
+    ///
+    /// It runs in the context of the walk, so for walks in a signal handler it
+    /// must be async-signal-safe.
+    ///
+    /// ```rust
+    /// use tracefp::TraceOptions;
+    ///
+    /// // Reject the lowest 64KiB, which Linux does not map by default
+    /// // (`vm.mmap_min_addr`), and everything beyond 47 bits.
+    /// let options = TraceOptions::new().fp_check(|fp| fp >= 0x10000 && fp < 1 << 47);
+    /// tracefp::trace_with_options(&options, |pc| {
+    ///     println!("{:#x}", pc);
+    ///     true
+    /// });
+    /// ```
+    pub fn fp_check(mut self, check: fn(u64) -> bool) -> Self {
+        self.fp_check = Some(FpCheck(check));
+        self
+    }
+
+    /// The bounds of the stack being unwound. Frame pointers outside of them
+    /// end the walk, and loads inside of them skip the memory access check.
+    ///
+    /// By default the bounds are detected automatically: the stack of the
+    /// calling thread as cached by [`StackBounds::current`], or its alternate
+    /// signal stack, whichever contains the stack pointer of the topmost
+    /// frame. The [`trace`](crate::trace) family (but not the `*_from_ucontext`
+    /// functions) fills the cache on first use. If neither contains the stack
+    /// pointer, e.g. when unwinding a context of another thread, no bounds are
+    /// applied. A walk starting on the alternate signal stack continues onto
+    /// the cached thread stack where the chain leaves the handler.
+    ///
+    /// `Some(bounds)` uses the given bounds instead, which must be readable
+    /// memory. Frame pointers within them are followed even outside of
+    /// user-space, e.g. on the stacks of a kernel. `None` disables the
+    /// validation.
+    pub fn stack_bounds(mut self, bounds: Option<StackBounds>) -> Self {
+        self.stack_bounds = match bounds {
+            Some(bounds) => StackBoundsMode::Fixed(bounds),
+            None => StackBoundsMode::Disabled,
+        };
+        self
+    }
+}
@@ -0,0 +1,778 @@
+//! A sampling CPU profiler.
+//!
+//! [`Profiler::start`] arms a `ITIMER_PROF` interval timer, so the kernel
+//! interrupts whichever thread is consuming CPU time with `SIGPROF` at the
+//! given frequency. The handler unwinds the interrupted thread and stores its
+//! stack in a [`SampleBuffer`] without taking locks or allocating. A
+//! collector thread moves the samples out of the buffer into a table of
+//! stacks, which [`Profiler::report`] returns.
+//!
+//! ```rust,no_run
+//! let profiler = tracefp::profiler::Profiler::start(99).unwrap();
+//! // ... the code to profile ...
+//! let report = profiler.report();
+//! for stack in &report.stacks {
+//!     println!("{} samples: {:x?}", stack.count, stack.stack);
+//! }
+//! ```
+//!
+//! The collector thread consumes CPU time too and is sampled like any other
+//! thread. Its samples are kept apart in [`Report::internal`], so the
+//! overhead of the profiler can be read from its own output.
+//!
+//! Several consumers, e.g. an exporter and an in-memory flight recorder, can
+//! receive every sample with [`Hooks::on_sample`]. Stacks are captured once
+//! and shared between them, see [`Sample`].
+//!
+//! Only one profiler can run at a time. The `SIGPROF` handler is installed on
+//! first use and must not be replaced. If `SIGPROF` already has another
+//! handler, e.g. of another copy of tracefp linked into the process,
+//! [`Profiler::start`] fails with [`io::ErrorKind::AlreadyExists`].
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::aggregate::StackId;
+use crate::sample::SampleBuffer;
+pub use crate::sample::MAX_SAMPLE_FRAMES;
+
+// The number of samples the buffer holds until the collector drains it.
+const CAPACITY: usize = 1024;
+// The same for the samples of the collector thread.
+const INTERNAL_CAPACITY: usize = 64;
+// How often the collector drains the buffer.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+
+// The buffer the handler writes to, allocated on first use and kept for the
+// lifetime of the process, since a late signal may still be writing to it.
+static BUFFER: AtomicPtr<SampleBuffer> = AtomicPtr::new(std::ptr::null_mut());
+// The buffer for the samples of the collector thread, allocated with `BUFFER`.
+static INTERNAL_BUFFER: AtomicPtr<SampleBuffer> = AtomicPtr::new(std::ptr::null_mut());
+// The `pthread_t` of the collector thread, 0 if there is none.
+static COLLECTOR: AtomicU64 = AtomicU64::new(0);
+// The drop count of the buffer when the running profiler started.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// Whether a profiler exists, and whether the handler records samples.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+
+/// The number of samples taken with one stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ProfileStack {
+    /// The PCs of the stack, innermost frame first, as passed to the closure of
+    /// [`trace`](crate::trace).
+    pub stack: Vec<u64>,
+    /// Number of samples taken with this stack.
+    pub count: u64,
+    /// Whether the outermost frames of the stack are missing, because it was
+    /// cut off after [`MAX_SAMPLE_FRAMES`] frames or its walk stopped early,
+    /// e.g. at a bogus frame pointer.
+    pub truncated: bool,
+}
+
+/// The samples collected by a [`Profiler`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Report {
+    /// The sampled stacks, most frequent first.
+    pub stacks: Vec<ProfileStack>,
+    /// Number of samples lost because the buffer was full.
+    pub dropped: u64,
+    /// The stacks sampled on the collector thread of the profiler itself,
+    /// most frequent first. They are not part of `stacks`.
+    pub internal: Vec<ProfileStack>,
+}
+
+impl Report {
+    /// Total number of samples in the report, without the
+    /// [`internal`](Report::internal) ones.
+    pub fn samples(&self) -> u64 {
+        self.stacks.iter().map(|s| s.count).sum()
+    }
+
+    /// Writes the stacks like [`folded_with_truncation`](crate::report::folded_with_truncation),
+    /// followed by the [`internal`](Report::internal) ones below a
+    /// [`report::INTERNAL`](crate::report::INTERNAL) frame.
+    pub fn folded<W, F>(&self, mut writer: W, mut name: F) -> io::Result<()>
+    where
+        W: io::Write,
+        F: FnMut(u64) -> String,
+    {
+        use crate::report::{folded_with_root, INTERNAL};
+        folded_with_root(&mut writer, None, entries(&self.stacks), &mut name)?;
+        folded_with_root(&mut writer, Some(INTERNAL), entries(&self.internal), name)
+    }
+
+    /// Writes the report in the binary format of
+    /// [`report::encode`](crate::report::encode), including the
+    /// [`dropped`](Report::dropped) samples, the [`internal`](Report::internal)
+    /// stacks and which stacks are [`truncated`](ProfileStack::truncated).
+    /// Read it back with [`Report::decode`].
+    pub fn encode<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        crate::report::encode_records(writer, self.dropped, self.records())
+    }
+
+    /// Same as [`Report::encode`], but into `buffer`, like
+    /// [`report::encode_to_slice`](crate::report::encode_to_slice).
+    ///
+    /// Returns the number of bytes written, or `Err` with the number of bytes
+    /// needed if `buffer` is too small.
+    pub fn encode_to_slice(&self, buffer: &mut [u8]) -> Result<usize, usize> {
+        crate::report::encode_records_to_slice(buffer, self.dropped, self.records())
+    }
+
+    /// Reads a report written by [`Report::encode`]. The output of
+    /// [`report::encode`](crate::report::encode) reads as a report without
+    /// dropped samples or internal stacks.
+    pub fn decode<R: io::Read>(reader: R) -> io::Result<Report> {
+        crate::report::decode_report(reader)
+    }
+
+    // The stacks and the internal ones as input of `report::encode_records`.
+    fn records(&self) -> impl Iterator<Item = (&[u64], u64, bool, bool)> {
+        let stacks = entries(&self.stacks).map(|(stack, count, truncated)| (stack, count, truncated, false));
+        let internal = entries(&self.internal).map(|(stack, count, truncated)| (stack, count, truncated, true));
+        stacks.chain(internal)
+    }
+}
+
+// The stacks as input of the functions of `report`.
+fn entries(stacks: &[ProfileStack]) -> impl Iterator<Item = (&[u64], u64, bool)> {
+    stacks.iter().map(|s| (&s.stack[..], s.count, s.truncated))
+}
+
+/// A sample passed to the consumers registered with [`Hooks::on_sample`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Sample {
+    /// The id of [`stack`](Sample::stack).
+    pub id: StackId,
+    /// The PCs of the stack, innermost frame first. Samples with the same
+    /// stack share it for as long as any consumer keeps one.
+    pub stack: Arc<[u64]>,
+    /// Whether the outermost frames of the stack are missing, see
+    /// [`ProfileStack::truncated`].
+    pub truncated: bool,
+    /// Whether the sample was taken on the collector thread, see
+    /// [`Report::internal`].
+    pub internal: bool,
+}
+
+// A sampled stack, whether it was truncated and whether it was sampled on the
+// collector thread.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    stack: Vec<u64>,
+    truncated: bool,
+    internal: bool,
+}
+
+type Table = HashMap<Key, u64>;
+
+// The samples collected so far, and those of the current window of
+// `Hooks::on_window_complete`.
+#[derive(Default)]
+struct Tables {
+    all: Table,
+    window: Table,
+    // The number of dropped samples when the current window started.
+    window_dropped: u64,
+}
+type Callback<T> = Box<dyn Fn(&T) + Send + Sync>;
+
+/// Callbacks on the events of a [`Profiler`], for integrating its health
+/// into the telemetry of an application.
+///
+/// The callbacks run on the collector thread of the profiler, except for
+/// [`on_start`](Hooks::on_start), [`on_sample`](Hooks::on_sample),
+/// [`on_window_complete`](Hooks::on_window_complete) and
+/// [`on_error`](Hooks::on_error), which may also run on the thread starting,
+/// reporting or stopping it.
+#[derive(Default)]
+pub struct Hooks {
+    on_start: Option<Box<dyn Fn() + Send + Sync>>,
+    on_sample: Vec<Callback<Sample>>,
+    on_sample_dropped: Option<Callback<u64>>,
+    on_window_complete: Option<(Duration, Callback<Report>)>,
+    on_error: Option<Callback<io::Error>>,
+    // The stacks of the samples passed to `on_sample`, so that samples with
+    // the same stack share it.
+    stacks: Mutex<HashSet<Arc<[u64]>>>,
+}
+
+impl Hooks {
+    /// Creates hooks that do nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called once sampling has started.
+    pub fn on_start<F: Fn() + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_start = Some(Box::new(f));
+        self
+    }
+
+    /// Called with every sample. Can be registered several times, all
+    /// consumers receive every sample.
+    ///
+    /// The samples arrive in batches, every few milliseconds and on every
+    /// [`Profiler::report`]. Within a batch the samples of the application
+    /// come in the order they were taken, followed by those of the collector
+    /// thread (see [`Sample::internal`]), so the latter may arrive after
+    /// samples taken later. The consumers are called without any lock of the
+    /// profiler held and may call [`Profiler::report`] themselves.
+    pub fn on_sample<F: Fn(&Sample) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_sample.push(Box::new(f));
+        self
+    }
+
+    /// Called with the number of samples dropped since the last call, when
+    /// the buffer was full.
+    pub fn on_sample_dropped<F: Fn(u64) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_sample_dropped = Some(Box::new(move |n| f(*n)));
+        self
+    }
+
+    /// Called every `window` with the samples taken in that window. The
+    /// windows cover all samples of [`Profiler::report`], the last and
+    /// partial one is reported when the profiler stops, unless it is empty.
+    pub fn on_window_complete<F: Fn(&Report) + Send + Sync + 'static>(mut self, window: Duration, f: F) -> Self {
+        self.on_window_complete = Some((window, Box::new(f)));
+        self
+    }
+
+    /// Called with errors of starting or stopping the profiler, which are
+    /// returned as well if possible.
+    pub fn on_error<F: Fn(&io::Error) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+
+    fn error(&self, err: io::Error) -> io::Error {
+        if let Some(f) = &self.on_error {
+            f(&err);
+        }
+        err
+    }
+
+    // The sample of `key` for the `on_sample` consumers, `None` if there are
+    // none.
+    fn sample(&self, stacks: &mut HashSet<Arc<[u64]>>, key: &Key) -> Option<Sample> {
+        if self.on_sample.is_empty() {
+            return None;
+        }
+        let stack = match stacks.get(&key.stack[..]) {
+            Some(v) => v.clone(),
+            None => {
+                let stack: Arc<[u64]> = key.stack.as_slice().into();
+                stacks.insert(stack.clone());
+                stack
+            }
+        };
+        Some(Sample {
+            id: StackId::new(&stack),
+            stack,
+            truncated: key.truncated,
+            internal: key.internal,
+        })
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks").finish_non_exhaustive()
+    }
+}
+
+/// A running sampling profiler, which stops when dropped.
+pub struct Profiler {
+    tables: Arc<Mutex<Tables>>,
+    hooks: Arc<Hooks>,
+    stop: Arc<AtomicBool>,
+    collector: Option<JoinHandle<()>>,
+}
+
+impl Profiler {
+    /// Starts sampling the process `frequency_hz` times per second of
+    /// consumed CPU time.
+    ///
+    /// Fails with [`io::ErrorKind::AlreadyExists`] if another profiler is
+    /// running or `SIGPROF` has a foreign handler, and with
+    /// [`io::ErrorKind::InvalidInput`] if the frequency is not between 1 and
+    /// 1000000.
+    pub fn start(frequency_hz: u32) -> io::Result<Self> {
+        Self::start_with_hooks(frequency_hz, Hooks::default())
+    }
+
+    /// Same as [`start`](Profiler::start), but calls `hooks` on the events of
+    /// the profiler.
+    pub fn start_with_hooks(frequency_hz: u32, hooks: Hooks) -> io::Result<Self> {
+        if !(1..=1_000_000).contains(&frequency_hz) {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "invalid frequency");
+            return Err(hooks.error(err));
+        }
+        if RUNNING.swap(true, Ordering::Acquire) {
+            let err = io::Error::new(io::ErrorKind::AlreadyExists, "profiler already running");
+            return Err(hooks.error(err));
+        }
+        if let Err(err) = install() {
+            RUNNING.store(false, Ordering::Release);
+            return Err(hooks.error(err));
+        }
+        let tables = Arc::new(Mutex::new(Tables::default()));
+        let hooks = Arc::new(hooks);
+        let stop = Arc::new(AtomicBool::new(false));
+        let collector = {
+            let tables = tables.clone();
+            let hooks = hooks.clone();
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("tracefp-profiler".to_string())
+                .spawn(move || collect(&tables, &hooks, &stop))
+        };
+        let mut profiler = Self {
+            tables,
+            hooks,
+            stop,
+            collector: None,
+        };
+        match collector {
+            Ok(v) => profiler.collector = Some(v),
+            Err(err) => {
+                RUNNING.store(false, Ordering::Release);
+                return Err(profiler.hooks.error(err));
+            }
+        }
+        SAMPLING.store(true, Ordering::Release);
+        if let Err(err) = set_timer(1_000_000 / frequency_hz as libc::suseconds_t) {
+            let err = profiler.hooks.error(err);
+            // Dropping the profiler cleans up.
+            drop(profiler);
+            return Err(err);
+        }
+        if let Some(f) = &profiler.hooks.on_start {
+            f();
+        }
+        Ok(profiler)
+    }
+
+    /// The samples collected so far.
+    pub fn report(&self) -> Report {
+        drain(&self.tables, &self.hooks);
+        let tables = self.tables.lock().unwrap_or_else(|e| e.into_inner());
+        report(&tables.all, dropped())
+    }
+
+    /// Stops sampling and returns all collected samples.
+    pub fn stop(mut self) -> Report {
+        self.shutdown();
+        let tables = self.tables.lock().unwrap_or_else(|e| e.into_inner());
+        report(&tables.all, dropped())
+    }
+
+    // Stop sampling and the collector thread, then collect the last samples
+    // and report the partial window. Does nothing the second time.
+    fn shutdown(&mut self) {
+        let Some(collector) = self.collector.take() else {
+            return;
+        };
+        if let Err(err) = set_timer(0) {
+            self.hooks.error(err);
+        }
+        SAMPLING.store(false, Ordering::Release);
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = collector.join();
+        drain(&self.tables, &self.hooks);
+        if let Some((_, f)) = &self.hooks.on_window_complete {
+            let window = take_window(&self.tables, dropped());
+            if window.samples() > 0 || !window.internal.is_empty() || window.dropped > 0 {
+                f(&window);
+            }
+        }
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        self.shutdown();
+        // Samples of this profiler must not show up in the next one.
+        if let Some(buffer) = buffer() {
+            buffer.drain(|_| {});
+            DROPPED.store(buffer.dropped(), Ordering::Relaxed);
+        }
+        if let Some(buffer) = internal_buffer() {
+            buffer.drain(|_| {});
+        }
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+impl std::fmt::Debug for Profiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Profiler").finish_non_exhaustive()
+    }
+}
+
+fn install() -> io::Result<()> {
+    static ALLOCATE: Once = Once::new();
+    ALLOCATE.call_once(|| {
+        let buffer = Box::new(SampleBuffer::new(INTERNAL_CAPACITY));
+        INTERNAL_BUFFER.store(Box::into_raw(buffer), Ordering::Release);
+        let buffer = Box::new(SampleBuffer::new(CAPACITY));
+        BUFFER.store(Box::into_raw(buffer), Ordering::Release);
+    });
+    crate::signal::install(libc::SIGPROF, handler)
+}
+
+fn set_timer(interval_us: libc::suseconds_t) -> io::Result<()> {
+    let interval = libc::timeval {
+        tv_sec: (interval_us / 1_000_000) as libc::time_t,
+        tv_usec: interval_us % 1_000_000,
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn buffer() -> Option<&'static SampleBuffer> {
+    unsafe { BUFFER.load(Ordering::Acquire).as_ref() }
+}
+
+fn internal_buffer() -> Option<&'static SampleBuffer> {
+    unsafe { INTERNAL_BUFFER.load(Ordering::Acquire).as_ref() }
+}
+
+fn current_thread() -> u64 {
+    unsafe { libc::pthread_self() as usize as u64 }
+}
+
+// The number of samples the running profiler dropped.
+fn dropped() -> u64 {
+    buffer().map_or(0, |b| b.dropped()) - DROPPED.load(Ordering::Relaxed)
+}
+
+// Move the samples from the buffers into `tables`, passing each into the
+// `on_sample` consumers of `hooks` too.
+fn drain(tables: &Mutex<Tables>, hooks: &Hooks) {
+    let mut samples = vec![];
+    {
+        let mut tables = tables.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stacks = hooks.stacks.lock().unwrap_or_else(|e| e.into_inner());
+        drain_into(&mut tables, &mut stacks, hooks, &mut samples);
+    }
+    // The consumers may call back into the profiler, so no lock is held.
+    for sample in &samples {
+        for f in &hooks.on_sample {
+            f(sample);
+        }
+    }
+    drop(samples);
+    // Forget the stacks no consumer kept.
+    let mut stacks = hooks.stacks.lock().unwrap_or_else(|e| e.into_inner());
+    stacks.retain(|stack| Arc::strong_count(stack) > 1);
+}
+
+// Move the samples from the buffers into `tables`, and those for the
+// `on_sample` consumers of `hooks` into `samples`.
+fn drain_into(tables: &mut Tables, stacks: &mut HashSet<Arc<[u64]>>, hooks: &Hooks, samples: &mut Vec<Sample>) {
+    for (buffer, internal) in [(buffer(), false), (internal_buffer(), true)] {
+        let Some(buffer) = buffer else {
+            continue;
+        };
+        buffer.drain_with_truncation(|pcs, truncated| {
+            let key = Key {
+                stack: pcs.to_vec(),
+                truncated,
+                internal,
+            };
+            samples.extend(hooks.sample(stacks, &key));
+            if hooks.on_window_complete.is_some() {
+                *tables.window.entry(key.clone()).or_default() += 1;
+            }
+            *tables.all.entry(key).or_default() += 1;
+        });
+    }
+}
+
+// Take the samples of the current window out of `tables`, as a report of
+// the window that ends with `dropped` samples dropped.
+fn take_window(tables: &Mutex<Tables>, dropped: u64) -> Report {
+    let mut tables = tables.lock().unwrap_or_else(|e| e.into_inner());
+    let window = std::mem::take(&mut tables.window);
+    let window_dropped = std::mem::replace(&mut tables.window_dropped, dropped);
+    report(&window, dropped - window_dropped)
+}
+
+fn report(table: &Table, dropped: u64) -> Report {
+    let mut stacks = vec![];
+    let mut internal = vec![];
+    for (key, count) in table {
+        let stack = ProfileStack {
+            stack: key.stack.clone(),
+            count: *count,
+            truncated: key.truncated,
+        };
+        match key.internal {
+            true => internal.push(stack),
+            false => stacks.push(stack),
+        }
+    }
+    for stacks in [&mut stacks, &mut internal] {
+        stacks.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.stack.cmp(&b.stack))
+                .then(a.truncated.cmp(&b.truncated))
+        });
+    }
+    Report {
+        stacks,
+        dropped,
+        internal,
+    }
+}
+
+// The loop of the collector thread.
+fn collect(tables: &Mutex<Tables>, hooks: &Hooks, stop: &AtomicBool) {
+    let mut window_start = Instant::now();
+    let mut reported_dropped = 0;
+    COLLECTOR.store(current_thread(), Ordering::Relaxed);
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(DRAIN_INTERVAL);
+        drain(tables, hooks);
+        let dropped = dropped();
+        if dropped > reported_dropped {
+            if let Some(f) = &hooks.on_sample_dropped {
+                f(&(dropped - reported_dropped));
+            }
+            reported_dropped = dropped;
+        }
+        if let Some((length, f)) = &hooks.on_window_complete {
+            if window_start.elapsed() >= *length {
+                f(&take_window(tables, dropped));
+                window_start = Instant::now();
+            }
+        }
+    }
+    COLLECTOR.store(0, Ordering::Relaxed);
+}
+
+extern "C" fn handler(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+    let errno = unsafe { *errno_location() };
+    let buffer = match COLLECTOR.load(Ordering::Relaxed) == current_thread() {
+        true => internal_buffer(),
+        false => buffer(),
+    };
+    if let Some(buffer) = buffer.filter(|_| SAMPLING.load(Ordering::Acquire)) {
+        buffer.push_from_ucontext(ucontext);
+    }
+    unsafe { *errno_location() = errno };
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        fn assert_serde<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+        assert_serde::<Report>();
+        assert_serde::<ProfileStack>();
+        assert_serde::<crate::Frame>();
+        assert_serde::<crate::aggregate::StackId>();
+        assert_serde::<crate::jit::JitRegionId>();
+        assert_serde::<crate::merge::MergedStack>();
+        #[cfg(target_os = "linux")]
+        assert_serde::<crate::ThreadTrace>();
+        #[cfg(target_os = "linux")]
+        assert_serde::<crate::PoolThread>();
+    }
+
+    #[inline(never)]
+    fn burn(duration: Duration) -> u64 {
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < duration {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(1));
+        }
+        n
+    }
+
+    #[test]
+    fn test_profiler() {
+        assert!(Profiler::start(0).is_err());
+        let profiler = Profiler::start(1000).unwrap();
+        assert_eq!(Profiler::start(1000).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        burn(Duration::from_millis(200));
+        assert!(profiler.report().samples() > 0);
+        let report = profiler.stop();
+        assert!(report.samples() > 0);
+        assert!(report.stacks.iter().all(|s| !s.stack.is_empty()));
+        assert!(report.stacks.windows(2).all(|w| w[0].count >= w[1].count));
+
+        // A new profiler starts from scratch.
+        let profiler = Profiler::start(1).unwrap();
+        assert_eq!(profiler.report().samples(), 0);
+        drop(profiler);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let started = Arc::new(AtomicBool::new(false));
+        let hooks = {
+            let started = started.clone();
+            Hooks::new()
+                .on_start(move || started.store(true, Ordering::Relaxed))
+                .on_window_complete(Duration::from_millis(50), move |report| {
+                    let _ = tx.send(report.samples());
+                })
+        };
+        let profiler = Profiler::start_with_hooks(1000, hooks).unwrap();
+        assert!(started.load(Ordering::Relaxed));
+        // Samples drained by polling count towards the windows as well, and
+        // the partial window is reported on stop.
+        for _ in 0..4 {
+            burn(Duration::from_millis(50));
+            profiler.report();
+        }
+        let report = profiler.stop();
+        let windows: Vec<_> = rx.try_iter().collect();
+        assert!(windows.len() >= 2);
+        assert_eq!(windows.iter().sum::<u64>(), report.samples());
+
+        let errors = Arc::new(AtomicBool::new(false));
+        let hooks = {
+            let errors = errors.clone();
+            Hooks::new().on_error(move |_| errors.store(true, Ordering::Relaxed))
+        };
+        assert!(Profiler::start_with_hooks(0, hooks).is_err());
+        assert!(errors.load(Ordering::Relaxed));
+
+        // Every consumer receives every sample.
+        let recorder = Arc::new(Mutex::new(vec![]));
+        let exported = Arc::new(AtomicU64::new(0));
+        let hooks = {
+            let recorder = recorder.clone();
+            let exported = exported.clone();
+            Hooks::new()
+                .on_sample(move |sample| recorder.lock().unwrap().push(sample.clone()))
+                .on_sample(move |sample| {
+                    assert_eq!(sample.id, StackId::new(&sample.stack));
+                    exported.fetch_add(1, Ordering::Relaxed);
+                })
+        };
+        let profiler = Profiler::start_with_hooks(1000, hooks).unwrap();
+        burn(Duration::from_millis(200));
+        let report = profiler.stop();
+        let recorder = recorder.lock().unwrap();
+        assert!(!recorder.is_empty());
+        assert_eq!(recorder.len() as u64, exported.load(Ordering::Relaxed));
+        assert_eq!(recorder.iter().filter(|s| !s.internal).count() as u64, report.samples());
+        // Samples with the same stack share it.
+        for (a, b) in recorder.iter().zip(&recorder[1..]) {
+            if a.id == b.id {
+                assert!(Arc::ptr_eq(&a.stack, &b.stack));
+            }
+        }
+        drop(recorder);
+
+        // A consumer calling back into the profiler, like a flight recorder
+        // taking a snapshot, does not deadlock.
+        let slot: Arc<Mutex<Option<Profiler>>> = Arc::new(Mutex::new(None));
+        let snapshots = Arc::new(AtomicU64::new(0));
+        let hooks = {
+            let slot = slot.clone();
+            let snapshots = snapshots.clone();
+            let nested = AtomicBool::new(false);
+            Hooks::new().on_sample(move |_| {
+                if nested.swap(true, Ordering::Relaxed) {
+                    return;
+                }
+                if let Some(profiler) = &*slot.lock().unwrap() {
+                    profiler.report();
+                    snapshots.fetch_add(1, Ordering::Relaxed);
+                }
+                nested.store(false, Ordering::Relaxed);
+            })
+        };
+        *slot.lock().unwrap() = Some(Profiler::start_with_hooks(1000, hooks).unwrap());
+        burn(Duration::from_millis(200));
+        let profiler = slot.lock().unwrap().take().unwrap();
+        profiler.stop();
+        assert!(snapshots.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_report_folded() {
+        let stack = |stack, count, truncated| ProfileStack {
+            stack,
+            count,
+            truncated,
+        };
+        let report = Report {
+            stacks: vec![stack(vec![2, 1], 3, false), stack(vec![1], 1, true)],
+            dropped: 0,
+            internal: vec![stack(vec![3], 2, false)],
+        };
+        assert_eq!(report.samples(), 4);
+        let mut out = vec![];
+        report.folded(&mut out, |pc| format!("f{}", pc)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "f1;f2 3\n[truncated];f1 1\n[tracefp-internal];f3 2\n"
+        );
+    }
+
+    #[test]
+    fn test_report_encode() {
+        let stack = |stack, count, truncated| ProfileStack {
+            stack,
+            count,
+            truncated,
+        };
+        let report = Report {
+            stacks: vec![stack(vec![2, 1], 3, false), stack(vec![1], 1, true)],
+            dropped: 5,
+            internal: vec![stack(vec![3], 2, true)],
+        };
+        let mut out = vec![];
+        report.encode(&mut out).unwrap();
+        assert_eq!(Report::decode(&out[..]).unwrap(), report);
+        let mut buffer = vec![0u8; out.len()];
+        assert_eq!(report.encode_to_slice(&mut buffer), Ok(out.len()));
+        assert_eq!(buffer, out);
+        assert_eq!(report.encode_to_slice(&mut buffer[1..]), Err(out.len()));
+
+        // The functions of `report` see the stacks only.
+        assert_eq!(
+            crate::report::decode_with_truncation(&out[..]).unwrap(),
+            [(vec![2, 1], 3, false), (vec![1], 1, true)]
+        );
+        let mut stacks = vec![];
+        crate::report::encode_with_truncation(&mut stacks, entries(&report.stacks)).unwrap();
+        let decoded = Report::decode(&stacks[..]).unwrap();
+        assert_eq!(decoded.stacks, report.stacks);
+        assert_eq!((decoded.dropped, decoded.internal.len()), (0, 0));
+    }
+}
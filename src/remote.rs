@@ -0,0 +1,303 @@
+//! Unwinding of threads of other processes on Linux.
+//!
+//! [`Thread::attach`] stops a thread with ptrace, after which its call-stack
+//! is unwound with the same frame-pointer walk as a local one, reading the
+//! frame records with `process_vm_readv(2)`. The thread continues when the
+//! [`Thread`] is dropped.
+//!
+//! ```rust,no_run
+//! let thread = tracefp::remote::Thread::attach(1234).unwrap();
+//! thread
+//!     .trace(|pc| {
+//!         println!("{:#x}", pc);
+//!         true
+//!     })
+//!     .unwrap();
+//! ```
+//!
+//! Attaching requires the same permissions as attaching a debugger, see
+//! `ptrace(2)` and the Yama `ptrace_scope` setting.
+
+use std::io;
+use std::ptr::null_mut;
+
+use crate::{MemoryReader, Registers, TraceOptions, TraceOutcome, UnwindCursor};
+
+// The note type of the general purpose registers for PTRACE_GETREGSET.
+const NT_PRSTATUS: usize = 1;
+
+/// Reads the memory of another process with `process_vm_readv(2)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProcessMemory {
+    pid: libc::pid_t,
+}
+
+impl ProcessMemory {
+    /// Creates a reader for the memory of process (or thread) `pid`.
+    pub fn new(pid: libc::pid_t) -> Self {
+        Self { pid }
+    }
+
+    fn read<T: Copy + Default>(&self, address: u64) -> Option<T> {
+        let mut value = T::default();
+        let size = std::mem::size_of::<T>();
+        let local = libc::iovec {
+            iov_base: &mut value as *mut T as *mut libc::c_void,
+            iov_len: size,
+        };
+        let remote = libc::iovec {
+            iov_base: address as *mut libc::c_void,
+            iov_len: size,
+        };
+        let res = unsafe { libc::process_vm_readv(self.pid, &local, 1, &remote, 1, 0) };
+        if res == size as isize {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl MemoryReader for ProcessMemory {
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        self.read(address)
+    }
+
+    fn read_u32(&self, address: u64) -> Option<u32> {
+        self.read(address)
+    }
+}
+
+/// A thread of another process, stopped by ptrace while this value lives.
+#[derive(Debug)]
+pub struct Thread {
+    tid: libc::pid_t,
+}
+
+impl Thread {
+    /// Attaches to thread `tid` and waits until it is stopped.
+    pub fn attach(tid: libc::pid_t) -> io::Result<Self> {
+        unsafe {
+            if libc::ptrace(
+                libc::PTRACE_ATTACH,
+                tid,
+                null_mut::<libc::c_void>(),
+                null_mut::<libc::c_void>(),
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // Detaches on error from now on.
+        let thread = Self { tid };
+        let mut status = 0;
+        loop {
+            if unsafe { libc::waitpid(tid, &mut status, libc::__WALL) } != -1 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if !libc::WIFSTOPPED(status) {
+            return Err(io::Error::other("thread exited before it stopped"));
+        }
+        Ok(thread)
+    }
+
+    /// The id of the thread.
+    pub fn tid(&self) -> libc::pid_t {
+        self.tid
+    }
+
+    /// Reads the registers of the stopped thread.
+    pub fn registers(&self) -> io::Result<Registers> {
+        let mut regs = [0 as libc::c_ulong; 64];
+        let mut iov = libc::iovec {
+            iov_base: regs.as_mut_ptr() as *mut libc::c_void,
+            iov_len: std::mem::size_of_val(&regs),
+        };
+        unsafe {
+            if libc::ptrace(libc::PTRACE_GETREGSET, self.tid, NT_PRSTATUS, &mut iov) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(from_user_regs(&regs))
+    }
+
+    /// A reader for the memory of the thread's process.
+    pub fn memory(&self) -> ProcessMemory {
+        ProcessMemory::new(self.tid)
+    }
+
+    /// Inspects the call-stack of the stopped thread, passing all active PCs
+    /// into the closure provided, like [`trace`](crate::trace).
+    pub fn trace<F>(&self, f: F) -> io::Result<TraceOutcome>
+    where
+        F: FnMut(u64) -> bool,
+    {
+        self.trace_with_options(&TraceOptions::default(), f)
+    }
+
+    /// Same as [`trace`](Thread::trace), but unwinds according to `options`.
+    ///
+    /// Only explicitly given [`TraceOptions::stack_bounds`] are applied.
+    pub fn trace_with_options<F>(&self, options: &TraceOptions, mut f: F) -> io::Result<TraceOutcome>
+    where
+        F: FnMut(u64) -> bool,
+    {
+        let registers = self.registers()?;
+        Ok(
+            match UnwindCursor::new_with_reader(&registers, self.memory(), options) {
+                Some(cursor) => crate::walk(cursor, options, |frame| f(frame.pc)),
+                None => TraceOutcome::InvalidFramePointer,
+            },
+        )
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_DETACH,
+                self.tid,
+                null_mut::<libc::c_void>(),
+                null_mut::<libc::c_void>(),
+            );
+        }
+    }
+}
+
+// Pick the registers from the general purpose register set, which is laid
+// out like the kernel's `struct user_regs_struct`, as returned by ptrace and
+// stored in core dumps.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn from_user_regs(regs: &[libc::c_ulong]) -> Registers {
+    Registers {
+        pc: regs[16],
+        fp: regs[4],
+        sp: regs[19],
+        lr: 0,
+    }
+}
+
+#[cfg(target_arch = "x86")]
+pub(crate) fn from_user_regs(regs: &[libc::c_ulong]) -> Registers {
+    Registers {
+        pc: regs[12] as u64,
+        fp: regs[5] as u64,
+        sp: regs[15] as u64,
+        lr: 0,
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn from_user_regs(regs: &[libc::c_ulong]) -> Registers {
+    Registers {
+        pc: regs[32],
+        fp: regs[29],
+        sp: regs[31],
+        lr: regs[30],
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub(crate) fn from_user_regs(regs: &[libc::c_ulong]) -> Registers {
+    // The T bit of the CPSR, the frame pointer is r7 in Thumb state.
+    let thumb = regs[16] & (1 << 5) != 0;
+    let fp = if thumb { regs[7] } else { regs[11] };
+    Registers {
+        pc: regs[15] as u64 | thumb as u64,
+        fp: fp as u64,
+        sp: regs[13] as u64,
+        lr: 0,
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+pub(crate) fn from_user_regs(regs: &[libc::c_ulong]) -> Registers {
+    Registers {
+        pc: regs[0],
+        fp: regs[8],
+        sp: regs[2],
+        lr: 0,
+    }
+}
+
+#[cfg(target_arch = "loongarch64")]
+pub(crate) fn from_user_regs(regs: &[libc::c_ulong]) -> Registers {
+    Registers {
+        pc: regs[33],
+        fp: regs[22],
+        sp: regs[3],
+        lr: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_memory() {
+        let vals = [1u64, u64::MAX];
+        let memory = ProcessMemory::new(unsafe { libc::getpid() });
+        assert_eq!(memory.read_u64(vals.as_ptr() as u64), Some(1));
+        assert_eq!(memory.read_u32(&vals[1] as *const u64 as u64), Some(u32::MAX));
+        assert_eq!(memory.read_u64(0), None);
+    }
+
+    // Spins in code with frame pointers, unlike e.g. `pause()` in libc.
+    #[inline(never)]
+    fn spin() -> ! {
+        loop {
+            std::hint::spin_loop();
+        }
+    }
+
+    #[test]
+    fn test_attach() {
+        // The child reports when it is about to spin.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe { libc::write(fds[1], [0u8].as_ptr() as *const libc::c_void, 1) };
+            spin();
+        }
+        let mut buffer = [0u8];
+        unsafe {
+            assert_eq!(libc::read(fds[0], buffer.as_mut_ptr() as *mut libc::c_void, 1), 1);
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        let result = Thread::attach(pid).map(|thread| {
+            let registers = thread.registers().unwrap();
+            let mut pcs = vec![];
+            thread
+                .trace(|pc| {
+                    pcs.push(pc);
+                    true
+                })
+                .unwrap();
+            (registers, pcs)
+        });
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+        match result {
+            Ok((registers, pcs)) => {
+                assert_ne!(registers.sp, 0);
+                assert_eq!(pcs.first(), Some(&registers.pc));
+                assert!(pcs.len() > 1);
+            }
+            // Sandboxes commonly forbid ptrace.
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {}
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
@@ -0,0 +1,442 @@
+//! Output formats for aggregated stacks.
+
+use std::io::{self, Read};
+
+use crate::profiler::{ProfileStack, Report};
+
+// The header of the binary format, followed by a version byte.
+const MAGIC: &[u8; 4] = b"TFPS";
+const VERSION: u8 = 3;
+
+/// The frame [`folded_with_truncation`] puts in place of the missing frames
+/// of a truncated stack.
+pub const TRUNCATED: &str = "[truncated]";
+
+/// The frame [`Report::folded`](crate::profiler::Report::folded) puts below
+/// the stacks sampled on the collector thread of the profiler.
+pub const INTERNAL: &str = "[tracefp-internal]";
+
+/// Writes stacks in the folded format of Brendan Gregg's FlameGraph tools,
+/// one line per stack, e.g. `main;parse;read 123`, which `flamegraph.pl` and
+/// `inferno-flamegraph` turn into a flame graph.
+///
+/// `stacks` are pairs of PCs, innermost frame first as reported by
+/// [`trace`](crate::trace), and their counts. `name` gives the name of the
+/// frame of a PC. Semicolons and line breaks in names are replaced, since
+/// they separate frames and lines.
+///
+/// ```rust
+/// let profiler = tracefp::profiler::Profiler::start(99).unwrap();
+/// // ... the code to profile ...
+/// let report = profiler.stop();
+///
+/// let symbols = tracefp::symbols::SymbolTable::load();
+/// tracefp::report::folded_with_truncation(
+///     std::io::stdout().lock(),
+///     report.stacks.iter().map(|s| (&s.stack, s.count, s.truncated)),
+///     |pc| match symbols.resolve_symbol(pc) {
+///         Some(symbol) => symbol.name.clone(),
+///         None => format!("{:#x}", pc),
+///     },
+/// )
+/// .unwrap();
+/// ```
+pub fn folded<W, I, S, F>(writer: W, stacks: I, name: F) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (S, u64)>,
+    S: AsRef<[u64]>,
+    F: FnMut(u64) -> String,
+{
+    folded_with_truncation(
+        writer,
+        stacks.into_iter().map(|(stack, count)| (stack, count, false)),
+        name,
+    )
+}
+
+/// Same as [`folded`], but for triples of PCs, their counts and whether the
+/// stack was truncated, like [`ProfileStack::truncated`](crate::profiler::ProfileStack::truncated).
+///
+/// Truncated stacks start with a [`TRUNCATED`] frame where their outermost
+/// frames are missing, so a flame graph does not show them as complete
+/// stacks of the functions they happen to end in.
+pub fn folded_with_truncation<W, I, S, F>(writer: W, stacks: I, name: F) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (S, u64, bool)>,
+    S: AsRef<[u64]>,
+    F: FnMut(u64) -> String,
+{
+    folded_with_root(writer, None, stacks, name)
+}
+
+// Same as `folded_with_truncation`, with `root` below every stack.
+pub(crate) fn folded_with_root<W, I, S, F>(mut writer: W, root: Option<&str>, stacks: I, mut name: F) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (S, u64, bool)>,
+    S: AsRef<[u64]>,
+    F: FnMut(u64) -> String,
+{
+    let mut line = String::new();
+    for (stack, count, truncated) in stacks {
+        line.clear();
+        let prefix = root.into_iter().chain(truncated.then_some(TRUNCATED));
+        for (n, frame) in prefix.enumerate() {
+            if n > 0 {
+                line.push(';');
+            }
+            line.push_str(frame);
+        }
+        let prefixed = !line.is_empty();
+        for (n, pc) in stack.as_ref().iter().rev().enumerate() {
+            if n > 0 || prefixed {
+                line.push(';');
+            }
+            line.extend(name(*pc).chars().map(|c| match c {
+                ';' => ':',
+                '\n' | '\r' => ' ',
+                c => c,
+            }));
+        }
+        writeln!(writer, "{} {}", line, count)?;
+    }
+    writer.flush()
+}
+
+/// Writes stacks in a compact binary format, e.g. to send raw stacks to a
+/// process that symbolizes them, which reads them with [`decode`].
+///
+/// `stacks` are pairs of PCs and their counts, like for [`folded`]. The
+/// format is a 4-byte magic `TFPS`, a version byte and the number of dropped
+/// samples, followed by one record per stack: the count, the number of PCs
+/// shifted left by two with whether the stack was sampled on the collector
+/// thread and whether it was truncated in the lowest bits, and the PCs, all
+/// as LEB128 varints. The first PC is stored as is, every other one as the
+/// zigzag encoded difference to its predecessor, which is small for the
+/// frames of one module. [`Report::encode`] writes all of these, this writes
+/// no dropped samples and no collector thread stacks.
+///
+/// The records are staged in a buffer on the stack, so nothing is allocated.
+pub fn encode<W, I, S>(writer: W, stacks: I) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (S, u64)>,
+    S: AsRef<[u64]>,
+{
+    encode_with_truncation(writer, stacks.into_iter().map(|(stack, count)| (stack, count, false)))
+}
+
+/// Same as [`encode`], but for triples of PCs, their counts and whether the
+/// stack was truncated, like for [`folded_with_truncation`]. Read them with
+/// [`decode_with_truncation`].
+pub fn encode_with_truncation<W, I, S>(writer: W, stacks: I) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (S, u64, bool)>,
+    S: AsRef<[u64]>,
+{
+    let stacks = stacks
+        .into_iter()
+        .map(|(stack, count, truncated)| (stack, count, truncated, false));
+    encode_records(writer, 0, stacks)
+}
+
+// Same as `encode_with_truncation`, with `dropped` samples and whether each
+// stack was sampled on the collector thread.
+pub(crate) fn encode_records<W, I, S>(mut writer: W, dropped: u64, stacks: I) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (S, u64, bool, bool)>,
+    S: AsRef<[u64]>,
+{
+    let mut buffer = [0u8; 4096];
+    let mut len = 0;
+    encode_with(dropped, stacks, |bytes| {
+        if len + bytes.len() > buffer.len() {
+            writer.write_all(&buffer[..len])?;
+            len = 0;
+        }
+        buffer[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+        Ok(())
+    })?;
+    writer.write_all(&buffer[..len])?;
+    writer.flush()
+}
+
+/// Same as [`encode`], but into `buffer`, e.g. the preallocated message of a
+/// transport with a fixed memory budget.
+///
+/// Returns the number of bytes written, or `Err` with the number of bytes
+/// needed if `buffer` is too small, in which case its contents are
+/// unspecified.
+///
+/// ```rust
+/// let stacks = [(vec![0x1234, 0x1000], 3)];
+/// let mut buffer = [0u8; 8];
+/// let needed = tracefp::report::encode_to_slice(&mut buffer, stacks.iter().map(|(s, c)| (s, *c))).unwrap_err();
+/// let mut buffer = vec![0u8; needed];
+/// let len = tracefp::report::encode_to_slice(&mut buffer, stacks.iter().map(|(s, c)| (s, *c))).unwrap();
+/// assert_eq!(len, needed);
+/// ```
+pub fn encode_to_slice<I, S>(buffer: &mut [u8], stacks: I) -> Result<usize, usize>
+where
+    I: IntoIterator<Item = (S, u64)>,
+    S: AsRef<[u64]>,
+{
+    let stacks = stacks.into_iter().map(|(stack, count)| (stack, count, false, false));
+    encode_records_to_slice(buffer, 0, stacks)
+}
+
+// Same as `encode_records`, but into `buffer` like `encode_to_slice`.
+pub(crate) fn encode_records_to_slice<I, S>(buffer: &mut [u8], dropped: u64, stacks: I) -> Result<usize, usize>
+where
+    I: IntoIterator<Item = (S, u64, bool, bool)>,
+    S: AsRef<[u64]>,
+{
+    let mut len = 0;
+    let _ = encode_with(dropped, stacks, |bytes| {
+        if let Some(dst) = buffer.get_mut(len..len + bytes.len()) {
+            dst.copy_from_slice(bytes);
+        }
+        len += bytes.len();
+        Ok(())
+    });
+    match len <= buffer.len() {
+        true => Ok(len),
+        false => Err(len),
+    }
+}
+
+// Passes the encoding of `stacks` into `emit`, a few bytes at a time.
+fn encode_with<I, S, F>(dropped: u64, stacks: I, mut emit: F) -> io::Result<()>
+where
+    I: IntoIterator<Item = (S, u64, bool, bool)>,
+    S: AsRef<[u64]>,
+    F: FnMut(&[u8]) -> io::Result<()>,
+{
+    emit(MAGIC)?;
+    emit(&[VERSION])?;
+    let mut varint = |value| {
+        let mut bytes = [0u8; 10];
+        let len = encode_varint(&mut bytes, value);
+        emit(&bytes[..len])
+    };
+    varint(dropped)?;
+    for (stack, count, truncated, internal) in stacks {
+        let stack = stack.as_ref();
+        varint(count)?;
+        varint((stack.len() as u64) << 2 | (internal as u64) << 1 | truncated as u64)?;
+        let mut last = 0u64;
+        for (n, pc) in stack.iter().enumerate() {
+            if n == 0 {
+                varint(*pc)?;
+            } else {
+                let delta = pc.wrapping_sub(last) as i64;
+                varint(((delta << 1) ^ (delta >> 63)) as u64)?;
+            }
+            last = *pc;
+        }
+    }
+    Ok(())
+}
+
+/// Reads stacks written by [`encode`], as pairs of PCs and their counts.
+pub fn decode<R: io::Read>(reader: R) -> io::Result<Vec<(Vec<u64>, u64)>> {
+    let stacks = decode_with_truncation(reader)?;
+    Ok(stacks.into_iter().map(|(stack, count, _)| (stack, count)).collect())
+}
+
+/// Reads stacks written by [`encode_with_truncation`] or [`encode`], as
+/// triples of PCs, their counts and whether the stack was truncated.
+///
+/// The stacks sampled on the collector thread in the output of
+/// [`Report::encode`] are skipped, [`Report::decode`] reads them. Files of
+/// version 1, which did not record truncation, are read as well, with all
+/// stacks complete.
+pub fn decode_with_truncation<R: io::Read>(reader: R) -> io::Result<Vec<(Vec<u64>, u64, bool)>> {
+    let report = decode_report(reader)?;
+    Ok(report
+        .stacks
+        .into_iter()
+        .map(|s| (s.stack, s.count, s.truncated))
+        .collect())
+}
+
+// Reads the output of `encode_records`, or of the older versions.
+pub(crate) fn decode_report<R: io::Read>(reader: R) -> io::Result<Report> {
+    let mut bytes = io::BufReader::new(reader).bytes();
+    let mut header = [0u8; 5];
+    for byte in header.iter_mut() {
+        *byte = bytes.next().ok_or_else(|| invalid("truncated header"))??;
+    }
+    if header[..4] != *MAGIC {
+        return Err(invalid("not a tracefp stack file"));
+    }
+    let version = header[4];
+    if !(1..=VERSION).contains(&version) {
+        return Err(invalid("unsupported version"));
+    }
+    let mut report = Report::default();
+    if version >= 3 {
+        report.dropped = read_varint(&mut bytes)?.ok_or_else(|| invalid("truncated header"))?;
+    }
+    while let Some(count) = read_varint(&mut bytes)? {
+        let mut len = read_varint(&mut bytes)?.ok_or_else(|| invalid("truncated record"))?;
+        let (mut truncated, mut internal) = (false, false);
+        if version >= 2 {
+            truncated = len & 1 != 0;
+            len >>= 1;
+        }
+        if version >= 3 {
+            internal = len & 1 != 0;
+            len >>= 1;
+        }
+        let mut stack = Vec::with_capacity(len.min(1024) as usize);
+        let mut last = 0u64;
+        for n in 0..len {
+            let value = read_varint(&mut bytes)?.ok_or_else(|| invalid("truncated record"))?;
+            let pc = if n == 0 {
+                value
+            } else {
+                last.wrapping_add(((value >> 1) as i64 ^ -((value & 1) as i64)) as u64)
+            };
+            stack.push(pc);
+            last = pc;
+        }
+        let stack = ProfileStack {
+            stack,
+            count,
+            truncated,
+        };
+        match internal {
+            true => report.internal.push(stack),
+            false => report.stacks.push(stack),
+        }
+    }
+    Ok(report)
+}
+
+pub(crate) fn write_varint(buffer: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0u8; 10];
+    let len = encode_varint(&mut bytes, value);
+    buffer.extend_from_slice(&bytes[..len]);
+}
+
+// Encode a varint into `bytes`, returning its length.
+fn encode_varint(bytes: &mut [u8; 10], mut value: u64) -> usize {
+    let mut len = 0;
+    while value >= 0x80 {
+        bytes[len] = value as u8 | 0x80;
+        value >>= 7;
+        len += 1;
+    }
+    bytes[len] = value as u8;
+    len + 1
+}
+
+// Read a varint, `None` at the end of the input.
+pub(crate) fn read_varint<I: Iterator<Item = io::Result<u8>>>(bytes: &mut I) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match bytes.next() {
+            Some(v) => v?,
+            None if shift == 0 => return Ok(None),
+            None => return Err(invalid("truncated varint")),
+        };
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folded() {
+        let stacks = [(vec![3, 2, 1], 10), (vec![4, 1], 2)];
+        let mut out = vec![];
+        folded(&mut out, stacks.iter().map(|(s, c)| (s, *c)), |pc| match pc {
+            1 => "main".to_string(),
+            4 => "a;b\nc".to_string(),
+            pc => format!("f{}", pc),
+        })
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "main;f2;f3 10\nmain;a:b c 2\n");
+
+        let stacks = [(vec![2, 1], 3, true), (vec![], 1, true)];
+        let mut out = vec![];
+        folded_with_truncation(&mut out, stacks.iter().map(|(s, c, t)| (s, *c, *t)), |pc| {
+            format!("f{}", pc)
+        })
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[truncated];f1;f2 3\n[truncated] 1\n");
+    }
+
+    #[test]
+    fn test_encode() {
+        let stacks = vec![
+            (vec![0x5555_0000_1234, 0x5555_0000_1000, 0x7fff_0000_0000, 0], 3),
+            (vec![], 1),
+            (vec![u64::MAX, 0, u64::MAX], u64::MAX),
+        ];
+        let mut out = vec![];
+        encode(&mut out, stacks.iter().map(|(s, c)| (s, *c))).unwrap();
+        assert_eq!(&out[..6], b"TFPS\x03\x00");
+        assert_eq!(decode(&out[..]).unwrap(), stacks);
+
+        assert!(decode(&out[..out.len() - 1]).is_err());
+        assert!(decode(&b"TFPS\x04\x00"[..]).is_err());
+        assert!(decode(&b"TFP"[..]).is_err());
+        assert!(decode(&b"TFPS\x03"[..]).is_err());
+        assert!(decode(&b"TFPS\x03\x00"[..]).unwrap().is_empty());
+        // Version 1 had no flags, version 2 only the truncation one, neither
+        // the dropped samples.
+        assert_eq!(
+            decode_with_truncation(&b"TFPS\x01\x03\x02\x10\x02"[..]).unwrap(),
+            [(vec![0x10, 0x11], 3, false)]
+        );
+        assert_eq!(
+            decode_with_truncation(&b"TFPS\x02\x03\x05\x10\x02\x01\x02\x20"[..]).unwrap(),
+            [(vec![0x10, 0x11], 3, true), (vec![0x20], 1, false)]
+        );
+
+        let mut buffer = vec![0u8; out.len()];
+        assert_eq!(
+            encode_to_slice(&mut buffer, stacks.iter().map(|(s, c)| (s, *c))),
+            Ok(out.len())
+        );
+        assert_eq!(buffer, out);
+        let mut buffer = vec![0u8; out.len() - 1];
+        assert_eq!(
+            encode_to_slice(&mut buffer, stacks.iter().map(|(s, c)| (s, *c))),
+            Err(out.len())
+        );
+        assert_eq!(
+            encode_to_slice(&mut [], stacks.iter().map(|(s, c)| (s, *c))),
+            Err(out.len())
+        );
+
+        let stacks = vec![(vec![0x10, 0x11], 3, true), (vec![0x10], 1, false), (vec![], 2, true)];
+        let mut out = vec![];
+        encode_with_truncation(&mut out, stacks.iter().map(|(s, c, t)| (s, *c, *t))).unwrap();
+        assert_eq!(decode_with_truncation(&out[..]).unwrap(), stacks);
+
+        // Larger than the staging buffer of `encode`.
+        let stacks: Vec<_> = (0..1000u64).map(|n| (vec![n << 40, n], n)).collect();
+        let mut out = vec![];
+        encode(&mut out, stacks.iter().map(|(s, c)| (s, *c))).unwrap();
+        assert!(out.len() > 4096);
+        assert_eq!(decode(&out[..]).unwrap(), stacks);
+    }
+}
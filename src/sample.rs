@@ -0,0 +1,287 @@
+//! A lock-free buffer for stack samples taken in signal handlers.
+//!
+//! A [`SampleBuffer`] is a ring of preallocated slots, each holding one stack
+//! of up to [`MAX_SAMPLE_FRAMES`] PCs. Any number of signal handlers can push
+//! into it concurrently, and any number of threads can drain it, without
+//! allocating or taking locks. A push into a slot that was not drained yet
+//! fails and is counted in [`dropped`](SampleBuffer::dropped), so a slow
+//! consumer loses the newest samples rather than blocking the handler.
+//!
+//! ```rust
+//! use tracefp::sample::SampleBuffer;
+//!
+//! static BUFFER: std::sync::OnceLock<SampleBuffer> = std::sync::OnceLock::new();
+//!
+//! extern "C" fn handler(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+//!     if let Some(buffer) = BUFFER.get() {
+//!         buffer.push_from_ucontext(ucontext);
+//!     }
+//! }
+//!
+//! BUFFER.set(SampleBuffer::new(1024)).unwrap();
+//! // ... install `handler` for SIGPROF, and from another thread:
+//! BUFFER.get().unwrap().drain(|pcs| println!("{:x?}", pcs));
+//! ```
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// The maximum number of frames kept per sample, deeper stacks are cut off.
+pub const MAX_SAMPLE_FRAMES: usize = 128;
+
+// The states of a slot.
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const FULL: u8 = 2;
+const READING: u8 = 3;
+
+struct Slot {
+    state: AtomicU8,
+    len: UnsafeCell<usize>,
+    truncated: UnsafeCell<bool>,
+    pcs: UnsafeCell<[u64; MAX_SAMPLE_FRAMES]>,
+}
+
+/// A preallocated ring of stack samples, see the [module documentation](self).
+pub struct SampleBuffer {
+    slots: Box<[Slot]>,
+    head: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+// The contents of a slot are only accessed by the one thread that moved it
+// into the `WRITING` or `READING` state.
+unsafe impl Sync for SampleBuffer {}
+
+impl SampleBuffer {
+    /// Creates a buffer of `capacity` samples, at least one.
+    pub fn new(capacity: usize) -> Self {
+        let slots = (0..capacity.max(1))
+            .map(|_| Slot {
+                state: AtomicU8::new(EMPTY),
+                len: UnsafeCell::new(0),
+                truncated: UnsafeCell::new(false),
+                pcs: UnsafeCell::new([0; MAX_SAMPLE_FRAMES]),
+            })
+            .collect();
+        Self {
+            slots,
+            head: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of samples the buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The number of samples that could not be pushed because their slot was
+    /// still occupied.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Pushes the stack `pcs`, cut off after [`MAX_SAMPLE_FRAMES`] frames.
+    /// Returns whether there was room for it. Async-signal-safe.
+    pub fn push(&self, pcs: &[u64]) -> bool {
+        self.push_with(|buffer| {
+            let len = pcs.len().min(MAX_SAMPLE_FRAMES);
+            buffer[..len].copy_from_slice(&pcs[..len]);
+            (len, pcs.len() > len)
+        })
+    }
+
+    /// Unwinds the call-stack of a signal handler's `ucontext` like
+    /// [`trace_from_ucontext`](crate::trace_from_ucontext) and pushes it.
+    /// Returns whether there was room for it. Async-signal-safe.
+    pub fn push_from_ucontext(&self, ucontext: *mut libc::c_void) -> bool {
+        self.push_trace(|f| crate::trace_from_ucontext(ucontext, f))
+    }
+
+    // Push the stack `trace` passes into its closure, truncated if it was cut
+    // off or the walk did not reach the end of the chain.
+    fn push_trace<T>(&self, trace: T) -> bool
+    where
+        T: FnOnce(&mut dyn FnMut(u64) -> bool) -> crate::TraceOutcome,
+    {
+        self.push_with(|buffer| {
+            let mut len = 0;
+            let mut cut = false;
+            let outcome = trace(&mut |pc| {
+                // Only a frame past the limit tells a cut-off stack from one
+                // that is exactly as deep.
+                if len == MAX_SAMPLE_FRAMES {
+                    cut = true;
+                    return false;
+                }
+                buffer[len] = pc;
+                len += 1;
+                true
+            });
+            (len, cut || !outcome.is_complete())
+        })
+    }
+
+    fn push_with<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&mut [u64; MAX_SAMPLE_FRAMES]) -> (usize, bool),
+    {
+        let slot = &self.slots[self.head.fetch_add(1, Ordering::Relaxed) % self.slots.len()];
+        if slot
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe { (*slot.len.get(), *slot.truncated.get()) = f(&mut *slot.pcs.get()) };
+        slot.state.store(FULL, Ordering::Release);
+        true
+    }
+
+    /// Removes all complete samples, passing each one into the closure
+    /// provided, roughly oldest first. Samples pushed concurrently may be
+    /// left for the next call.
+    pub fn drain<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u64]),
+    {
+        self.drain_with_truncation(|pcs, _| f(pcs))
+    }
+
+    /// Same as [`drain`](SampleBuffer::drain), but also passes whether the
+    /// sample is missing its outermost frames: it was cut off after
+    /// [`MAX_SAMPLE_FRAMES`] frames, or its walk stopped early, see
+    /// [`TraceOutcome::is_complete`](crate::TraceOutcome::is_complete).
+    pub fn drain_with_truncation<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u64], bool),
+    {
+        let start = self.head.load(Ordering::Relaxed);
+        for n in 0..self.slots.len() {
+            let slot = &self.slots[start.wrapping_add(n) % self.slots.len()];
+            if slot
+                .state
+                .compare_exchange(FULL, READING, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            let (pcs, len, truncated) = unsafe { (&*slot.pcs.get(), *slot.len.get(), *slot.truncated.get()) };
+            f(&pcs[..len], truncated);
+            slot.state.store(EMPTY, Ordering::Release);
+        }
+    }
+}
+
+impl std::fmt::Debug for SampleBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SampleBuffer")
+            .field("capacity", &self.capacity())
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(buffer: &SampleBuffer) -> Vec<Vec<u64>> {
+        let mut samples = vec![];
+        buffer.drain(|pcs| samples.push(pcs.to_vec()));
+        samples
+    }
+
+    #[test]
+    fn test_sample_buffer() {
+        let buffer = SampleBuffer::new(2);
+        assert!(buffer.push(&[1, 2]));
+        assert!(buffer.push(&[3]));
+        assert!(!buffer.push(&[4]));
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(take(&buffer), [vec![3], vec![1, 2]]);
+        assert!(take(&buffer).is_empty());
+
+        assert!(buffer.push(&[0; MAX_SAMPLE_FRAMES + 1]));
+        assert!(buffer.push(&[0; MAX_SAMPLE_FRAMES]));
+        let mut samples = vec![];
+        buffer.drain_with_truncation(|pcs, truncated| samples.push((pcs.len(), truncated)));
+        samples.sort_unstable();
+        assert_eq!(samples, [(MAX_SAMPLE_FRAMES, false), (MAX_SAMPLE_FRAMES, true)]);
+        assert_eq!(SampleBuffer::new(0).capacity(), 1);
+    }
+
+    // A stack of `depth` records at 0x7000 in some other address space, the
+    // outermost of which points at `last`.
+    struct Synthetic {
+        depth: u64,
+        last: u64,
+    }
+
+    impl crate::MemoryReader for Synthetic {
+        fn read_u64(&self, address: u64) -> Option<u64> {
+            let word = crate::WORD;
+            let n = address.checked_sub(0x7000)? / (2 * word);
+            if n >= self.depth {
+                return None;
+            }
+            match address.is_multiple_of(2 * word) {
+                true if n + 1 == self.depth => Some(self.last),
+                true => Some(address + 2 * word + crate::RECORD_OFFSET),
+                false => Some(0x2000 + n),
+            }
+        }
+
+        fn read_u32(&self, address: u64) -> Option<u32> {
+            self.read_u64(address).map(|v| v as u32)
+        }
+    }
+
+    #[test]
+    fn test_truncated_walks() {
+        let registers = crate::Registers::from_raw(0x1000, 0x7000 + crate::RECORD_OFFSET);
+        let push = |buffer: &SampleBuffer, depth, last| {
+            let reader = Synthetic { depth, last };
+            buffer.push_trace(|f| crate::trace_with_reader(&registers, &reader, f))
+        };
+        let buffer = SampleBuffer::new(8);
+        // Ends at the end of the chain.
+        assert!(push(&buffer, 3, 0));
+        // Ends at a record that cannot be read, or at a bogus frame pointer.
+        assert!(push(&buffer, 3, 0x9000_0000));
+        assert!(push(&buffer, 3, 0x7001));
+        // Ends at the frame limit.
+        assert!(push(&buffer, MAX_SAMPLE_FRAMES as u64 + 8, 0));
+        let mut samples = vec![];
+        buffer.drain_with_truncation(|pcs, truncated| samples.push((pcs.len(), truncated)));
+        samples.sort_unstable();
+        assert_eq!(samples, [(4, false), (4, true), (4, true), (MAX_SAMPLE_FRAMES, true)]);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let buffer = SampleBuffer::new(64);
+        let mut total = 0;
+        std::thread::scope(|s| {
+            for n in 0..4 {
+                let buffer = &buffer;
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        buffer.push(&[n; 4]);
+                    }
+                });
+            }
+            for _ in 0..100 {
+                buffer.drain(|pcs| {
+                    assert!(pcs.len() == 4 && pcs.iter().all(|pc| *pc == pcs[0]));
+                    total += 1;
+                });
+            }
+        });
+        buffer.drain(|_| total += 1);
+        assert_eq!(total + buffer.dropped(), 4000);
+    }
+}
@@ -0,0 +1,66 @@
+// Installation of the signal handlers of the crate.
+//
+// A process can contain several copies of tracefp, e.g. when two shared
+// libraries both embed it. The copies don't know each other's state, so
+// if one of them replaced the other's handler, the requests of the other
+// would never be answered or its buffers would never be filled. Instead a
+// handler is only installed over the default or ignore disposition, or over
+// the very same handler; any other handler is reported as an error, no
+// matter whether it belongs to another copy of tracefp or the application.
+
+use std::io;
+
+pub(crate) type Handler = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+// Install `handler` for `signal`, unless another handler is installed.
+pub(crate) fn install(signal: libc::c_int, handler: Handler) -> io::Result<()> {
+    unsafe {
+        let mut old: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(signal, std::ptr::null(), &mut old) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let handler = handler as libc::sighandler_t;
+        if old.sa_sigaction == handler {
+            return Ok(());
+        }
+        if old.sa_sigaction != libc::SIG_DFL && old.sa_sigaction != libc::SIG_IGN {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "signal {} already has a handler, which may belong to another copy of tracefp",
+                    signal
+                ),
+            ));
+        }
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn handler1(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+    extern "C" fn handler2(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+    #[test]
+    fn test_install() {
+        // Not used by the crate or the other tests.
+        let signal = libc::SIGWINCH;
+        install(signal, handler1).unwrap();
+        install(signal, handler1).unwrap();
+        let err = install(signal, handler2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+        install(signal, handler2).unwrap();
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+    }
+}
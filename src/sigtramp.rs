@@ -0,0 +1,150 @@
+// Detection of signal trampolines.
+//
+// When the kernel delivers a signal it sets up the handler to return into a
+// small trampoline that invokes `rt_sigreturn`, which restores the
+// interrupted context. The trampoline is `__restore_rt` in glibc and musl on
+// x86_64 and i686, and `__kernel_rt_sigreturn` in the vDSO elsewhere. None of
+// them have a symbol that could be looked up from a signal handler, so they
+// are recognized by their machine code, like gdb and libunwind do.
+
+use crate::memory::MemoryReader;
+#[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), target_os = "linux"))]
+use crate::Registers;
+
+// The instruction sequences a signal trampoline can start with, i.e. the
+// code a signal handler returns into.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[
+    // mov $15, %rax; syscall
+    &[0x48, 0xc7, 0xc0, 0x0f, 0x00, 0x00, 0x00, 0x0f, 0x05],
+];
+#[cfg(all(target_arch = "x86", target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[
+    // mov $173, %eax; int $0x80
+    &[0xb8, 0xad, 0x00, 0x00, 0x00, 0xcd, 0x80],
+    // pop %eax; mov $119, %eax; int $0x80
+    &[0x58, 0xb8, 0x77, 0x00, 0x00, 0x00, 0xcd, 0x80],
+];
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[
+    // mov x8, #139; svc #0
+    &[0x68, 0x11, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4],
+];
+#[cfg(all(target_arch = "arm", target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[
+    // mov r7, #173; svc #0
+    &[0xad, 0x70, 0xa0, 0xe3, 0x00, 0x00, 0x00, 0xef],
+    // mov r7, #119; svc #0
+    &[0x77, 0x70, 0xa0, 0xe3, 0x00, 0x00, 0x00, 0xef],
+    // movs r7, #173; svc #0 (Thumb)
+    &[0xad, 0x27, 0x00, 0xdf],
+    // movs r7, #119; svc #0 (Thumb)
+    &[0x77, 0x27, 0x00, 0xdf],
+];
+#[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[
+    // li a7, 139; ecall
+    &[0x93, 0x08, 0xb0, 0x08, 0x73, 0x00, 0x00, 0x00],
+];
+#[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[
+    // li.w a7, 139; syscall 0
+    &[0x0b, 0x2c, 0x82, 0x03, 0x00, 0x00, 0x2b, 0x00],
+];
+// `_sigtramp` on macOS is a regular function that calls the handler, so the
+// handler returns into the middle of it and there is no fixed sequence to
+// look for.
+#[cfg(not(target_os = "linux"))]
+pub(crate) const SIGRETURN: &[&[u8]] = &[];
+
+// Whether the return address `pc` points at the start of a signal trampoline.
+pub(crate) fn is_signal_trampoline<R: MemoryReader>(pc: u64, reader: &R) -> bool {
+    // Return addresses into Thumb code have the lowest bit set.
+    #[cfg(target_arch = "arm")]
+    let pc = pc & !1;
+    SIGRETURN.iter().any(|code| matches(pc, code, reader))
+}
+
+// The registers of the interrupted context a signal trampoline at `pc`
+// restores, read from the signal frame `sp` points at once the handler has
+// returned into the trampoline. `None` where the layout of the signal frame
+// is not known.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub(crate) fn interrupted<R: MemoryReader>(_pc: u64, sp: u64, reader: &R) -> Option<Registers> {
+    // The return address popped by the handler is the first field of the
+    // frame, the `ucontext` follows it.
+    let gregs =
+        (core::mem::offset_of!(libc::ucontext_t, uc_mcontext) + core::mem::offset_of!(libc::mcontext_t, gregs)) as u64;
+    let reg = |n: libc::c_int| reader.read_u64(sp + gregs + n as u64 * 8);
+    Some(Registers {
+        pc: reg(libc::REG_RIP)?,
+        fp: reg(libc::REG_RBP)?,
+        sp: reg(libc::REG_RSP)?,
+        lr: 0,
+    })
+}
+
+// The registers of the interrupted context a signal trampoline at `pc`
+// restores, read from the signal frame `sp` points at once the handler has
+// returned into the trampoline. `None` where the layout of the signal frame
+// is not known.
+#[cfg(all(target_arch = "x86", target_os = "linux"))]
+pub(crate) fn interrupted<R: MemoryReader>(pc: u64, sp: u64, reader: &R) -> Option<Registers> {
+    // Only `rt_sigreturn` frames hold a `ucontext`, pointed to by the third
+    // field after the popped return address: the signal, `&info` and `&uc`.
+    if !matches(pc, SIGRETURN[0], reader) {
+        return None;
+    }
+    let ucontext = reader.read_u32(sp + 8)? as u64;
+    let gregs =
+        (core::mem::offset_of!(libc::ucontext_t, uc_mcontext) + core::mem::offset_of!(libc::mcontext_t, gregs)) as u64;
+    let reg = |n: libc::c_int| reader.read_u32(ucontext + gregs + n as u64 * 4).map(u64::from);
+    Some(Registers {
+        pc: reg(libc::REG_EIP)?,
+        fp: reg(libc::REG_EBP)?,
+        sp: reg(libc::REG_ESP)?,
+        lr: 0,
+    })
+}
+
+// The registers of the interrupted context a signal trampoline at `pc`
+// restores, read from the signal frame `sp` points at once the handler has
+// returned into the trampoline. `None` where the layout of the signal frame
+// is not known.
+#[cfg(not(all(any(target_arch = "x86_64", target_arch = "x86"), target_os = "linux")))]
+pub(crate) fn interrupted<R: MemoryReader>(_pc: u64, _sp: u64, _reader: &R) -> Option<crate::Registers> {
+    None
+}
+
+// Whether the code at `pc` starts with `code`, which is read as two possibly
+// overlapping words, the first and the last one.
+fn matches<R: MemoryReader>(pc: u64, code: &[u8], reader: &R) -> bool {
+    let n = code.len();
+    if n >= 8 {
+        let head = reader.read_u64(pc).map(u64::to_ne_bytes);
+        let tail = reader.read_u64(pc + n as u64 - 8).map(u64::to_ne_bytes);
+        head.is_some_and(|v| v == code[..8]) && tail.is_some_and(|v| v == code[n - 8..])
+    } else {
+        let head = reader.read_u32(pc).map(u32::to_ne_bytes);
+        let tail = reader.read_u32(pc + n as u64 - 4).map(u32::to_ne_bytes);
+        head.is_some_and(|v| v == code[..4]) && tail.is_some_and(|v| v == code[n - 4..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_signal_trampoline() {
+        let reader = crate::memory::LocalMemory::new();
+        let pc = test_is_signal_trampoline as fn() as usize as u64;
+        assert!(!is_signal_trampoline(pc, &reader));
+        for code in SIGRETURN {
+            let mut buffer = code.to_vec();
+            assert!(is_signal_trampoline(buffer.as_ptr() as u64, &reader));
+            buffer[0] ^= 0xff;
+            assert!(!is_signal_trampoline(buffer.as_ptr() as u64, &reader));
+        }
+    }
+}
@@ -0,0 +1,158 @@
+#[cfg(feature = "std")]
+use std::cell::Cell;
+#[cfg(feature = "std")]
+use std::mem::MaybeUninit;
+
+#[cfg(feature = "std")]
+thread_local! {
+    static CURRENT: Cell<Option<StackBounds>> = const { Cell::new(None) };
+}
+
+/// The address range `[start, end)` occupied by a stack.
+///
+/// Frame records can only live on the stack, so a frame pointer outside of
+/// the bounds of the stack being unwound is garbage and ends the walk. This
+/// catches the bogus frames that otherwise appear past the outermost frame,
+/// and every load inside the bounds is known to be safe without a memory
+/// access check.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StackBounds {
+    /// The lowest address of the stack.
+    pub start: u64,
+    /// The address past the highest address of the stack (its base, since
+    /// stacks grow downwards).
+    pub end: u64,
+}
+
+impl StackBounds {
+    /// Creates bounds covering `[start, end)`.
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end }
+    }
+
+    /// Returns the bounds of the calling thread's stack.
+    ///
+    /// The result is cached per thread. The first call on a thread asks the
+    /// threading library, which is **not** async-signal-safe (on Linux it may
+    /// allocate and read `/proc/self/maps`). Call it once on every thread that
+    /// will be unwound from a signal handler, later calls and
+    /// [`cached`](StackBounds::cached) are then safe.
+    #[cfg(feature = "std")]
+    pub fn current() -> Option<Self> {
+        if let Some(bounds) = Self::cached() {
+            return Some(bounds);
+        }
+        let bounds = query_current()?;
+        CURRENT.with(|v| v.set(Some(bounds)));
+        Some(bounds)
+    }
+
+    /// Returns the bounds of the calling thread's stack if they were already
+    /// queried by [`current`](StackBounds::current). Async-signal-safe.
+    #[cfg(feature = "std")]
+    pub fn cached() -> Option<Self> {
+        CURRENT.with(|v| v.get())
+    }
+
+    /// Returns the bounds of the calling thread's alternate signal stack, if
+    /// one is installed. Async-signal-safe.
+    #[cfg(feature = "std")]
+    pub fn altstack() -> Option<Self> {
+        let mut stack = MaybeUninit::<libc::stack_t>::uninit();
+        let stack = unsafe {
+            if libc::sigaltstack(std::ptr::null(), stack.as_mut_ptr()) != 0 {
+                return None;
+            }
+            stack.assume_init()
+        };
+        if stack.ss_flags & libc::SS_DISABLE != 0 || stack.ss_sp.is_null() {
+            return None;
+        }
+        let start = stack.ss_sp as u64;
+        Some(Self::new(start, start + stack.ss_size as u64))
+    }
+
+    /// Whether `address` lies within the bounds.
+    pub fn contains(&self, address: u64) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    /// Whether the `size` bytes at `address` lie within the bounds.
+    pub fn contains_range(&self, address: u64, size: u64) -> bool {
+        match address.checked_add(size) {
+            Some(end) => address >= self.start && end <= self.end,
+            None => false,
+        }
+    }
+
+    // The bounds of the stack an unwind starting at `sp` runs on: the cached
+    // thread stack or the alternate signal stack, whichever contains `sp`.
+    // For the alternate signal stack also the thread stack, which the chain
+    // continues on below the signal handler.
+    #[cfg(feature = "std")]
+    pub(crate) fn find(sp: u64) -> Option<(Self, Option<Self>)> {
+        let thread = Self::cached();
+        if let Some(bounds) = thread.filter(|b| b.contains(sp)) {
+            return Some((bounds, None));
+        }
+        Self::altstack().filter(|b| b.contains(sp)).map(|b| (b, thread))
+    }
+}
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn query_current() -> Option<StackBounds> {
+    unsafe {
+        let mut attr = MaybeUninit::<libc::pthread_attr_t>::uninit();
+        if libc::pthread_getattr_np(libc::pthread_self(), attr.as_mut_ptr()) != 0 {
+            return None;
+        }
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let res = libc::pthread_attr_getstack(attr.as_ptr(), &mut addr, &mut size);
+        libc::pthread_attr_destroy(attr.as_mut_ptr());
+        if res != 0 {
+            return None;
+        }
+        let start = addr as u64;
+        Some(StackBounds::new(start, start + size as u64))
+    }
+}
+
+#[cfg(all(feature = "std", target_os = "macos"))]
+fn query_current() -> Option<StackBounds> {
+    unsafe {
+        let thread = libc::pthread_self();
+        let end = libc::pthread_get_stackaddr_np(thread) as u64;
+        let size = libc::pthread_get_stacksize_np(thread) as u64;
+        Some(StackBounds::new(end.checked_sub(size)?, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current() {
+        let local = 0u64;
+        let bounds = StackBounds::current().unwrap();
+        assert!(bounds.contains(&local as *const u64 as u64));
+        assert_eq!(StackBounds::cached(), Some(bounds));
+        assert_eq!(StackBounds::find(&local as *const u64 as u64), Some((bounds, None)));
+
+        let heap = Box::new(0u64);
+        assert!(!bounds.contains(heap.as_ref() as *const u64 as u64));
+        assert_eq!(StackBounds::find(heap.as_ref() as *const u64 as u64), None);
+    }
+
+    #[test]
+    fn test_contains_range() {
+        let bounds = StackBounds::new(0x1000, 0x2000);
+        assert!(bounds.contains_range(0x1000, 16));
+        assert!(bounds.contains_range(0x1ff0, 16));
+        assert!(!bounds.contains_range(0x1ff8, 16));
+        assert!(!bounds.contains_range(0xff8, 16));
+        assert!(!bounds.contains_range(u64::MAX - 8, 16));
+    }
+}
@@ -0,0 +1,292 @@
+//! Symbolization that is safe to use from signal handlers.
+//!
+//! Resolving a PC with a general-purpose symbolizer such as
+//! `backtrace::resolve` allocates, takes locks and may read files, none of
+//! which is allowed in a signal handler. A [`SymbolTable`] instead reads the
+//! function symbols of all loaded modules once, at startup, after which
+//! [`resolve_symbol`](SymbolTable::resolve_symbol) is a plain binary search.
+//!
+//! ```rust
+//! let symbols = tracefp::symbols::SymbolTable::load();
+//! tracefp::trace(|pc| {
+//!     match symbols.resolve_symbol(pc) {
+//!         Some(symbol) => println!("{:#x} {}+{:#x}", pc, symbol.name, pc - symbol.address),
+//!         None => println!("{:#x}", pc),
+//!     }
+//!     true
+//! });
+//! ```
+//!
+//! Only the symbol tables (`.symtab` and `.dynsym` of ELF modules, `LC_SYMTAB`
+//! of Mach-O ones) are used, there is no line information, inlined functions
+//! are attributed to their caller, and names are not demangled. Stripped
+//! modules only have their exported functions.
+
+/// A function symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolInfo {
+    /// The (mangled) name of the symbol.
+    pub name: String,
+    /// The address of the first instruction of the function in memory.
+    pub address: u64,
+    /// The size of the function in bytes, 0 if unknown.
+    pub size: u64,
+}
+
+/// The function symbols of the modules of the current process.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    // Sorted by address, without duplicate addresses.
+    symbols: Vec<SymbolInfo>,
+}
+
+impl SymbolTable {
+    /// Reads the symbols of all modules currently loaded. Must not be called
+    /// from a signal handler.
+    ///
+    /// Modules whose symbols cannot be read are skipped.
+    pub fn load() -> Self {
+        #[cfg(target_os = "linux")]
+        return Self::for_modules(&crate::modules::ModuleMap::snapshot());
+        #[cfg(target_os = "macos")]
+        {
+            let mut symbols = vec![];
+            load(&mut symbols);
+            Self::new(symbols)
+        }
+    }
+
+    /// Reads the symbols of the files of `modules`, e.g. the modules of
+    /// another process from [`ModuleMap::for_process`](crate::modules::ModuleMap::for_process).
+    /// Linux only.
+    #[cfg(target_os = "linux")]
+    pub fn for_modules(modules: &crate::modules::ModuleMap) -> Self {
+        let mut symbols = vec![];
+        for module in modules.modules() {
+            if let Ok(data) = std::fs::read(&module.path) {
+                read_elf_symbols(&data, module.bias, &mut symbols);
+            }
+        }
+        Self::new(symbols)
+    }
+
+    fn new(mut symbols: Vec<SymbolInfo>) -> Self {
+        symbols.sort_by_key(|s| s.address);
+        symbols.dedup_by_key(|s| s.address);
+        Self { symbols }
+    }
+
+    /// All symbols, sorted by address.
+    pub fn symbols(&self) -> &[SymbolInfo] {
+        &self.symbols
+    }
+
+    /// Finds the function containing `pc`.
+    ///
+    /// This does not allocate or take locks, so it can be called from a
+    /// signal handler.
+    pub fn resolve_symbol(&self, pc: u64) -> Option<&SymbolInfo> {
+        let n = self.symbols.partition_point(|s| s.address <= pc).checked_sub(1)?;
+        let symbol = &self.symbols[n];
+        if symbol.size != 0 && pc - symbol.address >= symbol.size {
+            return None;
+        }
+        Some(symbol)
+    }
+}
+
+// Read the function symbols of the ELF file `data` of the native class,
+// loaded at `bias`.
+#[cfg(target_os = "linux")]
+fn read_elf_symbols(data: &[u8], bias: u64, symbols: &mut Vec<SymbolInfo>) -> Option<()> {
+    const SHT_SYMTAB: u32 = 2;
+    const SHT_DYNSYM: u32 = 11;
+    const STT_FUNC: u8 = 2;
+    const WORD: usize = std::mem::size_of::<usize>();
+
+    let u16_at = |offset: usize| Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?));
+    let u32_at = |offset: usize| Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?));
+    let word_at = |offset: usize| {
+        let mut word = [0u8; 8];
+        word[..WORD].copy_from_slice(data.get(offset..offset + WORD)?);
+        Some(u64::from_le_bytes(word) as usize)
+    };
+    if data.get(..4)? != b"\x7fELF" || *data.get(4)? as usize * 4 != WORD || *data.get(5)? != 1 {
+        return None;
+    }
+    let (shoff, shentsize, shnum) = match WORD {
+        8 => (word_at(40)?, u16_at(58)?, u16_at(60)?),
+        _ => (word_at(32)?, u16_at(46)?, u16_at(48)?),
+    };
+    // sh_type, sh_offset, sh_size and sh_link.
+    let section = |n: usize| {
+        let shdr = shoff + n * shentsize as usize;
+        match WORD {
+            8 => Some((
+                u32_at(shdr + 4)?,
+                word_at(shdr + 24)?,
+                word_at(shdr + 32)?,
+                u32_at(shdr + 40)?,
+            )),
+            _ => Some((
+                u32_at(shdr + 4)?,
+                word_at(shdr + 16)?,
+                word_at(shdr + 20)?,
+                u32_at(shdr + 24)?,
+            )),
+        }
+    };
+    for n in 0..shnum as usize {
+        let (kind, offset, size, link) = section(n)?;
+        if kind != SHT_SYMTAB && kind != SHT_DYNSYM {
+            continue;
+        }
+        let (_, strings, strings_size, _) = section(link as usize)?;
+        let strings = data.get(strings..strings + strings_size)?;
+        let entsize = if WORD == 8 { 24 } else { 16 };
+        for sym in (offset..offset + size).step_by(entsize) {
+            // st_name, st_info, st_shndx, st_value and st_size.
+            let (name, info, shndx, value, size) = match WORD {
+                8 => (
+                    u32_at(sym)?,
+                    *data.get(sym + 4)?,
+                    u16_at(sym + 6)?,
+                    word_at(sym + 8)?,
+                    word_at(sym + 16)?,
+                ),
+                _ => (
+                    u32_at(sym)?,
+                    *data.get(sym + 12)?,
+                    u16_at(sym + 14)?,
+                    word_at(sym + 4)?,
+                    word_at(sym + 8)?,
+                ),
+            };
+            if info & 0xf != STT_FUNC || shndx == 0 || value == 0 {
+                continue;
+            }
+            let name = strings.get(name as usize..)?;
+            let name = &name[..name.iter().position(|b| *b == 0)?];
+            symbols.push(SymbolInfo {
+                name: String::from_utf8_lossy(name).into_owned(),
+                address: bias.wrapping_add(value as u64),
+                size: size as u64,
+            });
+        }
+    }
+    Some(())
+}
+
+#[cfg(target_os = "macos")]
+fn load(symbols: &mut Vec<SymbolInfo>) {
+    use crate::modules::{load_commands, read_u32, read_u64, LC_SEGMENT_64};
+
+    const LC_SYMTAB: u32 = 0x2;
+    const N_STAB: u8 = 0xe0;
+    const N_TYPE: u8 = 0x0e;
+    const N_SECT: u8 = 0x0e;
+
+    unsafe {
+        for n in 0..crate::modules::_dyld_image_count() {
+            let header = crate::modules::_dyld_get_image_header(n);
+            if header.is_null() {
+                continue;
+            }
+            let slide = crate::modules::_dyld_get_image_vmaddr_slide(n) as u64;
+            // The symbol and string tables are in `__LINKEDIT`, at file
+            // offsets relative to it.
+            let mut linkedit = None;
+            let mut symtab = None;
+            for command in load_commands(header) {
+                match read_u32(command) {
+                    LC_SEGMENT_64 if std::slice::from_raw_parts(command.add(8), 16).starts_with(b"__LINKEDIT\0") => {
+                        let vmaddr = read_u64(command.add(24));
+                        let fileoff = read_u64(command.add(40));
+                        linkedit = Some(vmaddr.wrapping_add(slide).wrapping_sub(fileoff));
+                    }
+                    LC_SYMTAB => symtab = Some(command),
+                    _ => {}
+                }
+            }
+            let (Some(linkedit), Some(symtab)) = (linkedit, symtab) else {
+                continue;
+            };
+            let nlists = (linkedit + read_u32(symtab.add(8)) as u64) as *const u8;
+            let strings = (linkedit + read_u32(symtab.add(16)) as u64) as *const u8;
+            let strings = std::slice::from_raw_parts(strings, read_u32(symtab.add(20)) as usize);
+            let start = symbols.len();
+            for n in 0..read_u32(symtab.add(12)) as usize {
+                // struct nlist_64
+                let nlist = nlists.add(n * 16);
+                let kind = *nlist.add(4);
+                let value = read_u64(nlist.add(8));
+                if kind & N_STAB != 0 || kind & N_TYPE != N_SECT || value == 0 {
+                    continue;
+                }
+                let Some(name) = strings.get(read_u32(nlist) as usize..) else {
+                    continue;
+                };
+                let Some(end) = name.iter().position(|b| *b == 0) else {
+                    continue;
+                };
+                // C symbols have a leading underscore on Mach-O.
+                let name = name[..end].strip_prefix(b"_").unwrap_or(&name[..end]);
+                symbols.push(SymbolInfo {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    address: value.wrapping_add(slide),
+                    size: 0,
+                });
+            }
+            // Mach-O symbols have no size, a function ends where the next
+            // symbol of the image starts.
+            let image = &mut symbols[start..];
+            image.sort_by_key(|s| s.address);
+            for n in 1..image.len() {
+                image[n - 1].size = image[n].address - image[n - 1].address;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn test_function() -> u64 {
+        test_function as fn() -> u64 as usize as u64
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let symbols = SymbolTable::load();
+        assert!(!symbols.symbols().is_empty());
+
+        let pc = std::hint::black_box(test_function()) + 1;
+        let symbol = symbols.resolve_symbol(pc).unwrap();
+        assert!(symbol.name.contains("test_function"), "{}", symbol.name);
+        assert_eq!(symbol.address, pc - 1);
+
+        let pc = libc::getpid as unsafe extern "C" fn() -> libc::pid_t as usize as u64;
+        assert!(symbols.resolve_symbol(pc).unwrap().name.contains("getpid"));
+    }
+
+    #[test]
+    fn test_resolve_symbol() {
+        let symbol = |name: &str, address, size| SymbolInfo {
+            name: name.to_string(),
+            address,
+            size,
+        };
+        let symbols = SymbolTable::new(vec![
+            symbol("b", 0x2000, 0),
+            symbol("a", 0x1000, 0x100),
+            symbol("c", 0x2000, 8),
+        ]);
+        assert_eq!(symbols.symbols().len(), 2);
+        assert_eq!(symbols.resolve_symbol(0x10ff).unwrap().name, "a");
+        assert!(symbols.resolve_symbol(0x1100).is_none());
+        assert!(symbols.resolve_symbol(0xfff).is_none());
+        assert_eq!(symbols.resolve_symbol(0x9000).unwrap().name, "b");
+    }
+}
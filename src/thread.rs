@@ -0,0 +1,383 @@
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{Frame, TraceOptions};
+
+/// The maximum number of frames [`trace_thread`] returns.
+pub const MAX_THREAD_FRAMES: usize = 256;
+
+// The states of the single in-flight request.
+const IDLE: u64 = 0;
+const PENDING: u64 = 1;
+const WRITING: u64 = 2;
+const DONE: u64 = 3;
+
+// The request shared with the signal handler. `options` is written by the
+// requesting thread before the signal is sent, `frames` by the handler while
+// the state is `WRITING`.
+struct Slot {
+    options: UnsafeCell<MaybeUninit<TraceOptions>>,
+    frames: UnsafeCell<[MaybeUninit<Frame>; MAX_THREAD_FRAMES]>,
+}
+
+unsafe impl Sync for Slot {}
+
+static SLOT: Slot = Slot {
+    options: UnsafeCell::new(MaybeUninit::uninit()),
+    frames: UnsafeCell::new([const { MaybeUninit::uninit() }; MAX_THREAD_FRAMES]),
+};
+// The state of the request in the low 8 bits, the tid of its target in the
+// next 32 and a sequence number of the request in the rest. A handler only
+// moves the state on if tid and sequence number are still the ones it read,
+// so a late handler of a request that timed out cannot answer the next one.
+static STATE: AtomicU64 = AtomicU64::new(IDLE);
+// The sequence number of the last request.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static LEN: AtomicUsize = AtomicUsize::new(0);
+// Serializes the requests.
+static REQUEST: Mutex<()> = Mutex::new(());
+
+// The names of the threads that called `set_pool_thread`, by tid.
+static POOL_THREADS: Mutex<Vec<(libc::pid_t, PoolThread)>> = Mutex::new(Vec::new());
+
+// Removes the name of the thread from `POOL_THREADS` when it exits.
+struct Registration(Cell<Option<libc::pid_t>>);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(tid) = self.0.get() {
+            remove_pool_thread(tid);
+        }
+    }
+}
+
+thread_local! {
+    static REGISTRATION: Registration = const { Registration(Cell::new(None)) };
+}
+
+/// The signal [`trace_thread`] interrupts the target thread with,
+/// `SIGRTMIN + 1`.
+pub fn trace_thread_signal() -> libc::c_int {
+    libc::SIGRTMIN() + 1
+}
+
+/// Captures the call-stack of the thread `tid` of the current process.
+///
+/// The thread is interrupted with [`trace_thread_signal`] and unwinds itself
+/// from the handler's `ucontext`, the frames are handed back to the caller.
+/// The handler is installed on first use and must not be replaced, if the
+/// signal already has another handler this fails with
+/// [`io::ErrorKind::AlreadyExists`]. Syscalls
+/// the thread is blocked in are restarted if possible, but some (e.g.
+/// `nanosleep`) may still fail with `EINTR`.
+///
+/// Fails with [`io::ErrorKind::TimedOut`] if the thread does not respond
+/// within `timeout`, e.g. because it blocks the signal. At most
+/// [`MAX_THREAD_FRAMES`] frames are returned. Linux only.
+pub fn trace_thread(tid: libc::pid_t, timeout: Duration) -> io::Result<Vec<Frame>> {
+    trace_thread_with_options(tid, &TraceOptions::default(), timeout)
+}
+
+/// Same as [`trace_thread`], but unwinds according to `options`.
+pub fn trace_thread_with_options(
+    tid: libc::pid_t,
+    options: &TraceOptions,
+    timeout: Duration,
+) -> io::Result<Vec<Frame>> {
+    let _guard = REQUEST.lock().unwrap_or_else(|e| e.into_inner());
+    crate::signal::install(trace_thread_signal(), handler)?;
+    unsafe { (*SLOT.options.get()).write(*options) };
+    let request = request(SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1, tid);
+    STATE.store(request | PENDING, Ordering::Release);
+    let res = unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, trace_thread_signal()) };
+    if res != 0 {
+        STATE.store(IDLE, Ordering::Relaxed);
+        return Err(io::Error::last_os_error());
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        match STATE.load(Ordering::Acquire) ^ request {
+            DONE => break,
+            PENDING if Instant::now() >= deadline => {
+                // A handler that already started writing is waited for.
+                if STATE
+                    .compare_exchange(request | PENDING, IDLE, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "thread did not respond"));
+                }
+            }
+            _ => std::thread::sleep(Duration::from_micros(10)),
+        }
+    }
+    let len = LEN.load(Ordering::Relaxed);
+    let frames = unsafe { &*SLOT.frames.get() };
+    let frames = frames[..len].iter().map(|f| unsafe { f.assume_init() }).collect();
+    STATE.store(IDLE, Ordering::Relaxed);
+    Ok(frames)
+}
+
+// The bits of `STATE` above the state for request `sequence` to `tid`.
+fn request(sequence: u64, tid: libc::pid_t) -> u64 {
+    (sequence << 40) | ((tid as u32 as u64) << 8)
+}
+
+/// The logical name of a thread of a thread pool, see [`set_pool_thread`].
+///
+/// Displays as `pool(index)`, e.g. `rayon-worker(3)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct PoolThread {
+    /// The name of the pool.
+    pub pool: String,
+    /// The index of the thread within the pool, or of the task it runs.
+    pub index: usize,
+}
+
+impl fmt::Display for PoolThread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.pool, self.index)
+    }
+}
+
+/// Names the calling thread as thread `index` of the thread pool `pool`,
+/// which [`trace_all_threads`] reports in [`ThreadTrace::pool`], so that a
+/// dump shows e.g. `rayon-worker(3)` rather than a bare tid.
+///
+/// Meant to be called from the start handler of the pool's threads, or by a
+/// thread whenever it picks up another task. The name is forgotten when the
+/// thread exits or calls [`clear_pool_thread`]. Linux only.
+pub fn set_pool_thread(pool: &str, index: usize) {
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+    let name = PoolThread {
+        pool: pool.to_string(),
+        index,
+    };
+    let mut threads = POOL_THREADS.lock().unwrap_or_else(|e| e.into_inner());
+    match threads.iter_mut().find(|(t, _)| *t == tid) {
+        Some((_, v)) => *v = name,
+        None => threads.push((tid, name)),
+    }
+    drop(threads);
+    REGISTRATION.with(|r| r.0.set(Some(tid)));
+}
+
+/// Forgets the name given to the calling thread by [`set_pool_thread`].
+pub fn clear_pool_thread() {
+    if let Some(tid) = REGISTRATION.with(|r| r.0.take()) {
+        remove_pool_thread(tid);
+    }
+}
+
+fn remove_pool_thread(tid: libc::pid_t) {
+    let mut threads = POOL_THREADS.lock().unwrap_or_else(|e| e.into_inner());
+    threads.retain(|(t, _)| *t != tid);
+}
+
+// The name given to the thread `tid` by `set_pool_thread`.
+fn pool_thread(tid: libc::pid_t) -> Option<PoolThread> {
+    let threads = POOL_THREADS.lock().unwrap_or_else(|e| e.into_inner());
+    threads.iter().find(|(t, _)| *t == tid).map(|(_, v)| v.clone())
+}
+
+/// The call-stack of one thread, see [`trace_all_threads`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThreadTrace {
+    /// The id of the thread.
+    pub tid: libc::pid_t,
+    /// The name of the thread, as shown in `/proc/self/task/<tid>/comm`.
+    pub name: String,
+    /// The pool the thread belongs to, if it called [`set_pool_thread`].
+    pub pool: Option<PoolThread>,
+    /// The frames of the thread, innermost first. Empty if the thread did
+    /// not respond in time.
+    pub frames: Vec<Frame>,
+}
+
+/// Captures the call-stacks of all threads of the current process, including
+/// the calling one, e.g. to dump them when receiving `SIGQUIT`.
+///
+/// The threads are listed from `/proc/self/task` and traced one after the
+/// other with [`trace_thread`], each given `timeout` to respond. Threads that
+/// exit in the meantime are left out. Must not be called from a signal
+/// handler. Linux only.
+pub fn trace_all_threads(timeout: Duration) -> io::Result<Vec<ThreadTrace>> {
+    let mut traces = vec![];
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let tid = match entry.file_name().to_str().and_then(|v| v.parse().ok()) {
+            Some(v) => v,
+            None => continue,
+        };
+        let frames = match trace_thread(tid, timeout) {
+            Ok(v) => v,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => vec![],
+            Err(err) if err.raw_os_error() == Some(libc::ESRCH) => continue,
+            Err(err) => return Err(err),
+        };
+        let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        traces.push(ThreadTrace {
+            tid,
+            name: name.trim_end().to_string(),
+            pool: pool_thread(tid),
+            frames,
+        });
+    }
+    traces.sort_by_key(|t| t.tid);
+    Ok(traces)
+}
+
+extern "C" fn handler(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+    let errno = unsafe { *libc::__errno_location() };
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+    // Late signals of requests that timed out are ignored.
+    let state = STATE.load(Ordering::Relaxed);
+    let request = request(state >> 40, tid);
+    if state == request | PENDING
+        && STATE
+            .compare_exchange(state, request | WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    {
+        let options = unsafe { (*SLOT.options.get()).assume_init() };
+        let frames = unsafe { &mut *SLOT.frames.get() };
+        let mut len = 0;
+        crate::trace_frames_from_ucontext_with_options(ucontext, &options, |frame| {
+            frames[len].write(*frame);
+            len += 1;
+            len < MAX_THREAD_FRAMES
+        });
+        LEN.store(len, Ordering::Relaxed);
+        STATE.store(request | DONE, Ordering::Release);
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+
+    #[inline(never)]
+    fn spin(stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            std::hint::spin_loop();
+        }
+    }
+
+    #[test]
+    fn test_trace_thread() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let (tx, rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t)
+                .unwrap();
+            spin(&STOP);
+        });
+        let tid = rx.recv().unwrap();
+        let frames = trace_thread(tid, Duration::from_secs(10)).unwrap();
+        assert!(frames.len() > 1);
+        assert!(frames[0].is_top);
+
+        let options = TraceOptions::new().max_depth(1);
+        let frames = trace_thread_with_options(tid, &options, Duration::from_secs(10)).unwrap();
+        assert_eq!(frames.len(), 1);
+
+        STOP.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+        assert!(trace_thread(tid, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_trace_all_threads() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let (tx, rx) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("tracefp-test".to_string())
+            .spawn(move || {
+                set_pool_thread("tracefp-pool", 3);
+                tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t)
+                    .unwrap();
+                spin(&STOP);
+            })
+            .unwrap();
+        let tid = rx.recv().unwrap();
+        let traces = trace_all_threads(Duration::from_secs(10)).unwrap();
+        STOP.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+        // The name is gone with the thread.
+        assert_eq!(pool_thread(tid), None);
+
+        let me = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        assert!(traces.iter().any(|t| t.tid == me && !t.frames.is_empty()));
+        let trace = traces.iter().find(|t| t.tid == tid).unwrap();
+        assert_eq!(trace.name, "tracefp-test");
+        assert_eq!(trace.pool.as_ref().unwrap().to_string(), "tracefp-pool(3)");
+        assert!(trace.frames.len() > 1);
+        assert!(traces.iter().all(|t| t.tid == tid || t.pool.is_none()));
+
+        set_pool_thread("pool", 1);
+        set_pool_thread("pool", 2);
+        assert_eq!(pool_thread(me).map(|p| p.index), Some(2));
+        clear_pool_thread();
+        assert_eq!(pool_thread(me), None);
+    }
+
+    #[test]
+    fn test_late_handler() {
+        static UNBLOCK: AtomicBool = AtomicBool::new(false);
+        static STOP: AtomicBool = AtomicBool::new(false);
+        // Sends its tid and an address on its stack.
+        let spawn = |block: bool| {
+            let (tx, rx) = mpsc::channel();
+            let thread = std::thread::spawn(move || {
+                let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+                unsafe {
+                    libc::sigemptyset(&mut set);
+                    libc::sigaddset(&mut set, trace_thread_signal());
+                    if block {
+                        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+                    }
+                }
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+                tx.send((tid, &set as *const _ as u64)).unwrap();
+                if block {
+                    spin(&UNBLOCK);
+                    unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut()) };
+                }
+                spin(&STOP);
+            });
+            (thread, rx.recv().unwrap())
+        };
+        let (a, (a_tid, _)) = spawn(true);
+        let (b, (b_tid, b_stack)) = spawn(false);
+
+        // The signal stays pending on `a` until it unblocks it.
+        let err = trace_thread(a_tid, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Let the late handler of `a` run while the request to `b` is made.
+        UNBLOCK.store(true, Ordering::Relaxed);
+        for _ in 0..10 {
+            let frames = trace_thread(b_tid, Duration::from_secs(10)).unwrap();
+            assert!(frames[0].sp.abs_diff(b_stack) < 64 * 1024);
+        }
+
+        STOP.store(true, Ordering::Relaxed);
+        a.join().unwrap();
+        b.join().unwrap();
+    }
+
+    #[test]
+    fn test_trace_self() {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        let frames = trace_thread(tid, Duration::from_secs(10)).unwrap();
+        assert!(!frames.is_empty());
+    }
+}